@@ -0,0 +1,225 @@
+use std::{collections::HashMap, env, fs, path::Path, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+/// Environment variables starting with this override the config file, e.g. CSPOTV_MARKET
+const ENV_PREFIX: &str = "CSPOTV_";
+const DEFAULT_CLIENT_ID: &str = "fed3e6de8e3e4fe481b4020cdb72342e";
+/// Looked for in the working directory when no secret is configured
+const DEFAULT_CLIENT_SECRET_PATH: &str = "client_secret.txt";
+const DEFAULT_MARKET: &str = "GB";
+/// What the default client id has registered
+const DEFAULT_REDIRECT_URL: &str = "http://127.0.0.1";
+
+/// Everything that can be set at the top of the config file, in a profile or from the environment
+#[derive(Deserialize, Default)]
+struct Settings {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    client_secret_path: Option<PathBuf>,
+    redirect_url: Option<String>,
+    market: Option<String>,
+    music_path: Option<PathBuf>,
+    lib_path: Option<PathBuf>,
+    map_path: Option<PathBuf>,
+    progress_path: Option<PathBuf>,
+    playlist_id: Option<String>,
+    threshold: Option<f64>,
+    duration_tolerance: Option<u32>,
+    max_duration_delta: Option<u32>,
+    search_ladder: Option<Vec<String>>,
+    cache_ttl: Option<u64>,
+}
+
+impl Settings {
+    /// Settings in `other` take precedence over ours
+    fn merge(self, other: Settings) -> Settings {
+        Settings {
+            client_id: other.client_id.or(self.client_id),
+            client_secret: other.client_secret.or(self.client_secret),
+            client_secret_path: other.client_secret_path.or(self.client_secret_path),
+            redirect_url: other.redirect_url.or(self.redirect_url),
+            market: other.market.or(self.market),
+            music_path: other.music_path.or(self.music_path),
+            lib_path: other.lib_path.or(self.lib_path),
+            map_path: other.map_path.or(self.map_path),
+            progress_path: other.progress_path.or(self.progress_path),
+            playlist_id: other.playlist_id.or(self.playlist_id),
+            threshold: other.threshold.or(self.threshold),
+            duration_tolerance: other.duration_tolerance.or(self.duration_tolerance),
+            max_duration_delta: other.max_duration_delta.or(self.max_duration_delta),
+            search_ladder: other.search_ladder.or(self.search_ladder),
+            cache_ttl: other.cache_ttl.or(self.cache_ttl),
+        }
+    }
+
+    /// Relative paths in the config file are relative to the file, not the working directory
+    fn resolve_paths(mut self, dir: &Path) -> Settings {
+        for path in [
+            &mut self.client_secret_path,
+            &mut self.music_path,
+            &mut self.lib_path,
+            &mut self.map_path,
+            &mut self.progress_path,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = dir.join(&*path);
+        }
+        self
+    }
+
+    fn from_env() -> anyhow::Result<Settings> {
+        Ok(Settings {
+            client_id: env_var("CLIENT_ID")?,
+            client_secret: env_var("CLIENT_SECRET")?,
+            client_secret_path: env_var("CLIENT_SECRET_PATH")?,
+            redirect_url: env_var("REDIRECT_URL")?,
+            market: env_var("MARKET")?,
+            music_path: env_var("MUSIC_PATH")?,
+            lib_path: env_var("LIB_PATH")?,
+            map_path: env_var("MAP_PATH")?,
+            progress_path: env_var("PROGRESS_PATH")?,
+            playlist_id: env_var("PLAYLIST_ID")?,
+            threshold: env_var("THRESHOLD")?,
+            duration_tolerance: env_var("DURATION_TOLERANCE")?,
+            max_duration_delta: env_var("MAX_DURATION_DELTA")?,
+            // templates separated by ';'
+            search_ladder: env_var::<String>("SEARCH_LADDER")?
+                .map(|ladder| ladder.split(';').map(str::to_owned).collect()),
+            cache_ttl: env_var("CACHE_TTL")?,
+        })
+    }
+}
+
+fn env_var<T: FromStr>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let name = String::from(ENV_PREFIX) + name;
+    match env::var(&name) {
+        Ok(val) if !val.is_empty() => {
+            Ok(Some(val.parse().with_context(|| {
+                format!("invalid value \"{}\" for {}", val, name)
+            })?))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    #[serde(flatten)]
+    defaults: Settings,
+    #[serde(default)]
+    profiles: HashMap<String, Settings>,
+}
+
+/// Settings shared by every subcommand, command line arguments take precedence over these
+pub struct Config {
+    pub profile: Option<String>,
+    pub client_id: String,
+    /// Only needed for searching without logging in
+    pub client_secret: Option<String>,
+    /// Where spotify sends the browser after logging in, has to be registered as a redirect URI of
+    /// the client id's app. Listened on to capture the login, which is pasted in if that fails
+    pub redirect_url: String,
+    pub market: String,
+    pub music_path: Option<PathBuf>,
+    pub lib_path: Option<PathBuf>,
+    pub map_path: Option<PathBuf>,
+    pub progress_path: Option<PathBuf>,
+    pub playlist_id: Option<String>,
+    pub threshold: Option<f64>,
+    pub duration_tolerance: Option<u32>,
+    pub max_duration_delta: Option<u32>,
+    /// queries `map` searches with, see `QueryTemplate`
+    pub search_ladder: Option<Vec<String>>,
+    /// days that search results are cached for
+    pub cache_ttl: Option<u64>,
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("cspotv").join(CONFIG_FILE_NAME))
+}
+
+/// Reads `config_path`, or CSPOTV_CONFIG, or config.toml in the user's config directory if it
+/// exists, then applies `profile` (or CSPOTV_PROFILE) and the environment on top
+pub fn load(config_path: Option<PathBuf>, profile: Option<String>) -> anyhow::Result<Config> {
+    let config_path = config_path.or(env_var("CONFIG")?);
+    let explicit = config_path.is_some();
+    let config_file = match config_path.or_else(default_config_path) {
+        Some(path) if explicit || path.exists() => {
+            let contents = fs::read_to_string(&path).with_context(|| {
+                format!("could not read config file {}", path.to_string_lossy())
+            })?;
+            let mut config_file: ConfigFile = toml::from_str(&contents).with_context(|| {
+                format!("could not parse config file {}", path.to_string_lossy())
+            })?;
+            let dir = path.parent().unwrap_or(Path::new(""));
+            config_file.defaults = config_file.defaults.resolve_paths(dir);
+            config_file.profiles = config_file
+                .profiles
+                .into_iter()
+                .map(|(name, settings)| (name, settings.resolve_paths(dir)))
+                .collect();
+            config_file
+        }
+        _ => ConfigFile::default(),
+    };
+
+    let profile = profile.or(env_var("PROFILE")?);
+    let mut settings = config_file.defaults;
+    let mut profiles = config_file.profiles;
+    if let Some(name) = &profile {
+        let profile_settings = profiles
+            .remove(name)
+            .ok_or(anyhow!("No profile named \"{}\" in the config file", name))?;
+        settings = settings.merge(profile_settings);
+    }
+    let settings = settings.merge(Settings::from_env()?);
+
+    let client_secret =
+        match (settings.client_secret, settings.client_secret_path) {
+            (Some(secret), _) => Some(secret),
+            (None, Some(path)) => Some(fs::read_to_string(&path).with_context(|| {
+                format!("could not read client secret {}", path.to_string_lossy())
+            })?),
+            (None, None) => fs::read_to_string(DEFAULT_CLIENT_SECRET_PATH).ok(),
+        };
+
+    Ok(Config {
+        profile,
+        client_id: settings
+            .client_id
+            .unwrap_or_else(|| DEFAULT_CLIENT_ID.to_owned()),
+        client_secret: client_secret.map(|secret| secret.trim().to_owned()),
+        redirect_url: settings
+            .redirect_url
+            .unwrap_or_else(|| DEFAULT_REDIRECT_URL.to_owned()),
+        market: settings.market.unwrap_or_else(|| DEFAULT_MARKET.to_owned()),
+        music_path: settings.music_path,
+        lib_path: settings.lib_path,
+        map_path: settings.map_path,
+        progress_path: settings.progress_path,
+        playlist_id: settings.playlist_id,
+        threshold: settings.threshold,
+        duration_tolerance: settings.duration_tolerance,
+        max_duration_delta: settings.max_duration_delta,
+        search_ladder: settings.search_ladder,
+        cache_ttl: settings.cache_ttl,
+    })
+}
+
+/// The command line argument if there is one, otherwise the configured path
+pub fn path_or_config(
+    arg: Option<PathBuf>,
+    configured: &Option<PathBuf>,
+    name: &str,
+) -> anyhow::Result<PathBuf> {
+    arg.or_else(|| configured.clone())
+        .ok_or(anyhow!("No {} given and none set in the config file", name))
+}
@@ -1,17 +1,263 @@
 use anyhow::anyhow;
-use csv::Writer;
-use log::warn;
+use clap::Args;
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    fs::{self, File},
+    collections::HashMap,
+    fs,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::UNIX_EPOCH,
 };
-use symphonia::core::{io::MediaSourceStream, meta::StandardTagKey, probe::Hint};
+use symphonia::core::{
+    io::MediaSourceStream,
+    meta::{StandardTagKey, Tag, Value},
+    probe::Hint,
+};
+
+use crate::{collect_csv, LibRec};
+
+/// gitignore style file that can be placed in any directory of the library
+const IGNORE_FILE_NAME: &str = ".cspotignore";
+
+#[derive(Args)]
+pub struct LibOpts {
+    /// ignore the scan cache and probe every file again
+    #[arg(long)]
+    rescan: bool,
+    /// number of files to probe at once, defaults to the number of cpus
+    #[arg(short, long)]
+    jobs: Option<usize>,
+    /// only scan files matching this glob, relative to MUSIC_DIR (can be repeated)
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// skip files matching this glob, relative to MUSIC_DIR (can be repeated)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// file extensions that are probed for metadata
+    #[arg(
+        long = "ext",
+        value_name = "EXT",
+        value_delimiter = ',',
+        default_value = "aac,alac,caf,flac,m4a,mka,mkv,mp1,mp2,mp3,mp4,oga,ogg,opus,wav,webm"
+    )]
+    extensions: Vec<String>,
+    /// fill in missing tags from the file path, e.g. "{artist}/{album}/{track} - {title}.{ext}",
+    /// matched against the end of the path. the first template that matches is used (can be repeated)
+    #[arg(long = "template", value_name = "TEMPLATE")]
+    templates: Vec<String>,
+    /// separators between artists in a single artist tag, matched ignoring case (can be repeated)
+    #[arg(
+        long = "artist-delim",
+        value_name = "DELIM",
+        default_values = [";", " / ", " feat. ", " feat ", " ft. ", " featuring "]
+    )]
+    artist_delims: Vec<String>,
+}
+
+/// Splits every artist tag on `delims`, keeping the first of any duplicates
+fn split_artists(tags: &[String], delims: &[String]) -> Vec<String> {
+    let delims: Vec<String> = delims
+        .iter()
+        .filter(|d| !d.is_empty())
+        .map(|d| d.to_ascii_lowercase())
+        .collect();
+    let mut artists: Vec<String> = Vec::new();
+    for tag in tags {
+        let lower = tag.to_ascii_lowercase();
+        let mut start = 0;
+        while start <= tag.len() {
+            let next = delims
+                .iter()
+                .filter_map(|d| {
+                    lower[start..]
+                        .find(d.as_str())
+                        .map(|i| (start + i, d.len()))
+                })
+                .min();
+            let (end, delim_len) = next.unwrap_or((tag.len(), 0));
+            let artist = tag[start..end].trim();
+            if !artist.is_empty() && !artists.iter().any(|a| a.eq_ignore_ascii_case(artist)) {
+                artists.push(artist.to_owned());
+            }
+            start = end + delim_len.max(1);
+        }
+    }
+    artists
+}
+
+#[derive(Debug, PartialEq)]
+enum TemplatePart {
+    Lit(String),
+    Field(String),
+}
+
+/// A path template, made of one list of parts per path component
+struct PathTemplate {
+    segments: Vec<Vec<TemplatePart>>,
+}
+
+impl PathTemplate {
+    const FIELDS: [&'static str; 8] = [
+        "title",
+        "album",
+        "artist",
+        "albumartist",
+        "track",
+        "disc",
+        "year",
+        "ext",
+    ];
+
+    fn parse(template: &str) -> anyhow::Result<Self> {
+        let mut segments = Vec::new();
+        for seg in template.split('/') {
+            let mut parts = Vec::new();
+            let mut rest = seg;
+            while let Some(start) = rest.find('{') {
+                if start > 0 {
+                    parts.push(TemplatePart::Lit(rest[..start].to_owned()));
+                }
+                let end = rest[start..]
+                    .find('}')
+                    .ok_or(anyhow!("unclosed {{ in template \"{}\"", template))?;
+                let field = &rest[start + 1..start + end];
+                if field != "_" && !Self::FIELDS.contains(&field) {
+                    return Err(anyhow!(
+                        "unknown field {{{}}} in template \"{}\", expected one of {:?} or {{_}}",
+                        field,
+                        template,
+                        Self::FIELDS
+                    ));
+                }
+                parts.push(TemplatePart::Field(field.to_owned()));
+                rest = &rest[start + end + 1..];
+            }
+            if !rest.is_empty() {
+                parts.push(TemplatePart::Lit(rest.to_owned()));
+            }
+            segments.push(parts);
+        }
+        Ok(Self { segments })
+    }
+
+    /// Matches the template against the last components of `path`
+    fn match_path(&self, path: &str) -> Option<HashMap<String, String>> {
+        let comps: Vec<&str> = path.split(['/', '\\']).collect();
+        if comps.len() < self.segments.len() {
+            return None;
+        }
+        let mut fields = HashMap::new();
+        let comps = &comps[comps.len() - self.segments.len()..];
+        for (parts, comp) in self.segments.iter().zip(comps) {
+            if !match_parts(parts, comp, &mut fields) {
+                return None;
+            }
+        }
+        fields.remove("_");
+        Some(fields)
+    }
+}
+
+/// Matches `parts` against the whole of `text`, fields match as little as possible
+fn match_parts(parts: &[TemplatePart], text: &str, fields: &mut HashMap<String, String>) -> bool {
+    match parts.first() {
+        None => text.is_empty(),
+        Some(TemplatePart::Lit(lit)) => text
+            .strip_prefix(lit.as_str())
+            .is_some_and(|rest| match_parts(&parts[1..], rest, fields)),
+        Some(TemplatePart::Field(name)) => {
+            for (end, _) in text.char_indices().skip(1).chain([(text.len(), ' ')]) {
+                let val = &text[..end];
+                let valid = match name.as_str() {
+                    "track" | "disc" | "year" => val.chars().all(|c| c.is_ascii_digit()),
+                    "ext" => !val.contains('.'),
+                    _ => true,
+                };
+                if !valid {
+                    continue;
+                }
+                if match_parts(&parts[1..], &text[end..], fields) {
+                    fields.insert(name.to_owned(), val.trim().to_owned());
+                    return true;
+                }
+            }
+            false
+        }
+    }
+}
+
+/// Fills in the fields of `rec` that are missing with those matched from its path
+fn fill_from_path(rec: &mut LibRec, fields: HashMap<String, String>) {
+    let mut inferred = Vec::new();
+    for (field, val) in fields {
+        if val.is_empty() {
+            continue;
+        }
+        let (str_field, num_field) = match field.as_str() {
+            "title" => (Some(&mut rec.name), None),
+            "album" => (Some(&mut rec.album), None),
+            "artist" => (Some(&mut rec.artist), None),
+            "albumartist" => (Some(&mut rec.album_artist), None),
+            "track" => (None, Some(&mut rec.track_number)),
+            "disc" => (None, Some(&mut rec.disc_number)),
+            "year" => (None, Some(&mut rec.year)),
+            _ => (None, None),
+        };
+        if let Some(str_field) = str_field.filter(|f| f.trim().is_empty()) {
+            *str_field = val;
+            inferred.push(field);
+        } else if let Some(num_field) = num_field.filter(|f| f.is_none()) {
+            *num_field = val.parse().ok();
+            inferred.push(field);
+        }
+    }
+    inferred.sort();
+    rec.inferred = inferred.join(";");
+}
+
+/// Bump whenever `get_metadata` starts extracting something new, so old caches get reprobed
+const CACHE_VERSION: u32 = 4;
+
+/// Identifies a file on disk, if any of these change the file is probed again
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct CacheKey {
+    version: u32,
+    path: String,
+    size: u64,
+    mtime: u64,
+}
+
+type Cached = (CacheKey, LibRec);
+
+impl CacheKey {
+    fn new(path: &Path) -> anyhow::Result<Self> {
+        let meta = fs::metadata(path)?;
+        Ok(Self {
+            version: CACHE_VERSION,
+            path: path.to_string_lossy().into_owned(),
+            size: meta.len(),
+            mtime: meta.modified()?.duration_since(UNIX_EPOCH)?.as_millis() as u64,
+        })
+    }
+}
 
-use crate::LibRec;
+/// Parses the leading number out of values like "3/12" or "2011-03-04"
+fn leading_num(val: &str) -> Option<u32> {
+    let digits: String = val
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
 
 fn get_metadata(path: &Path) -> anyhow::Result<LibRec> {
     let extension = path.extension();
-    let src = std::fs::File::open(&path)?;
+    let src = std::fs::File::open(path)?;
     let mss = MediaSourceStream::new(Box::new(src), Default::default());
     let mut hint = Hint::new();
     if let Some(ext) = extension {
@@ -25,58 +271,267 @@ fn get_metadata(path: &Path) -> anyhow::Result<LibRec> {
         &Default::default(),
         &Default::default(),
     )?;
+    let track_id = probed.format.default_track().map(|track| track.id);
+    let duration_ms = probed.format.default_track().and_then(|track| {
+        let params = &track.codec_params;
+        let n_frames = params.n_frames?;
+        match (params.time_base, params.sample_rate) {
+            (Some(tb), _) => {
+                let time = tb.calc_time(n_frames);
+                Some((time.seconds * 1000 + (time.frac * 1000.0) as u64) as u32)
+            }
+            (None, Some(rate)) => Some((n_frames * 1000 / rate as u64) as u32),
+            (None, None) => None,
+        }
+    });
     let mut metadata = match probed.metadata.get() {
         Some(m) => m,
         None => probed.format.metadata(),
     };
-    let tags = metadata
-        .skip_to_latest()
-        .ok_or(anyhow!("Could not get current metadata from file"))?
-        .tags();
+    // files without any tags are still listed, so path templates can fill them in
+    let tags = match metadata.skip_to_latest() {
+        Some(rev) => rev.tags(),
+        None => &[],
+    };
 
-    let get_tag_str_val = |tags: &[symphonia::core::meta::Tag], tag_target| -> String {
-        let val = tags
-            .iter()
-            .find(|t| t.std_key.map_or(false, |t| t == tag_target));
+    let get_tag_str_val = |tags: &[Tag], tag_target| -> String {
+        let val = tags.iter().find(|t| t.std_key == Some(tag_target));
         if val.is_none() {
             return String::new();
         }
-        if let symphonia::core::meta::Value::String(ref str_val) = val.unwrap().value {
-            return str_val.to_owned();
+        match val.unwrap().value {
+            Value::String(ref str_val) => str_val.to_owned(),
+            Value::UnsignedInt(num) => num.to_string(),
+            Value::SignedInt(num) => num.to_string(),
+            _ => String::new(),
         }
-        return String::new();
     };
-    if get_tag_str_val(tags, StandardTagKey::TrackTitle).is_empty() {
-        dbg!(&path);
-    }
-    Ok(LibRec {
+    let year = [
+        StandardTagKey::Date,
+        StandardTagKey::ReleaseDate,
+        StandardTagKey::OriginalDate,
+    ]
+    .into_iter()
+    .find_map(|key| leading_num(&get_tag_str_val(tags, key)));
+    let mut rec = LibRec {
         name: get_tag_str_val(tags, StandardTagKey::TrackTitle),
         album: get_tag_str_val(tags, StandardTagKey::Album),
         artist: get_tag_str_val(tags, StandardTagKey::Artist),
-    })
+        duration_ms,
+        track_number: leading_num(&get_tag_str_val(tags, StandardTagKey::TrackNumber)),
+        disc_number: leading_num(&get_tag_str_val(tags, StandardTagKey::DiscNumber)),
+        album_artist: get_tag_str_val(tags, StandardTagKey::AlbumArtist),
+        year,
+        genre: get_tag_str_val(tags, StandardTagKey::Genre),
+        isrc: get_tag_str_val(tags, StandardTagKey::IdentIsrc),
+        path: String::new(),
+        track_key: String::new(),
+        mbid: get_tag_str_val(tags, StandardTagKey::MusicBrainzRecordingId)
+            .trim()
+            .to_owned(),
+        inferred: String::new(),
+        // every artist frame as is, split with `split_artists` when the library is written
+        artists: tags
+            .iter()
+            .filter(|t| t.std_key == Some(StandardTagKey::Artist))
+            .filter_map(|t| match t.value {
+                Value::String(ref str_val) => Some(str_val.to_owned()),
+                _ => None,
+            })
+            .collect(),
+    };
+
+    // hash only the audio packets so that retagging the file keeps the same key
+    let mut hasher = Sha256::new();
+    while let Ok(packet) = probed.format.next_packet() {
+        if Some(packet.track_id()) == track_id {
+            hasher.update(&packet.data);
+        }
+    }
+    rec.track_key = format!("sha256:{:x}", hasher.finalize());
+    Ok(rec)
 }
 
-fn write_all_metadata(dir: &Path, wtr: &mut Writer<File>) -> anyhow::Result<()> {
-    // TODO turn off symphonia logging
-    if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                write_all_metadata(&path, wtr)?;
-            } else {
-                match get_metadata(&path) {
-                    Ok(rec) => wtr.serialize(rec)?,
-                    Err(err) => warn!("{}: {}", path.to_string_lossy(), err),
-                }
+fn collect_files(music_path: &Path, opts: &LibOpts) -> anyhow::Result<Vec<PathBuf>> {
+    let mut overrides = OverrideBuilder::new(music_path);
+    for glob in &opts.include {
+        overrides.add(glob)?;
+    }
+    for glob in &opts.exclude {
+        overrides.add(&format!("!{}", glob))?;
+    }
+    let walker = WalkBuilder::new(music_path)
+        .standard_filters(false)
+        .hidden(true)
+        .add_custom_ignore_filename(IGNORE_FILE_NAME)
+        .overrides(overrides.build()?)
+        .build();
+
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = entry?;
+        if !entry.file_type().is_some_and(|ft| ft.is_file()) {
+            continue;
+        }
+        let allowed = entry
+            .path()
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .is_some_and(|ext| opts.extensions.iter().any(|e| e.to_lowercase() == ext));
+        if allowed {
+            files.push(entry.into_path());
+        }
+    }
+    Ok(files)
+}
+
+/// Probes `paths` on `jobs` threads, results are returned in the same order as `paths`
+fn probe_all(paths: &[&Path], jobs: usize) -> Vec<anyhow::Result<LibRec>> {
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<anyhow::Result<LibRec>>> = paths.iter().map(|_| None).collect();
+    thread::scope(|s| {
+        let workers: Vec<_> = (0..jobs.max(1))
+            .map(|_| {
+                s.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= paths.len() {
+                            break;
+                        }
+                        done.push((i, get_metadata(paths[i])));
+                    }
+                    done
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (i, res) in worker.join().expect("probe worker panicked") {
+                results[i] = Some(res);
             }
         }
+    });
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+fn load_cache(cache_path: &PathBuf) -> HashMap<String, Cached> {
+    if !cache_path.exists() {
+        return HashMap::new();
+    }
+    match collect_csv::<Cached>(cache_path, false) {
+        Ok(recs) => recs
+            .into_iter()
+            .map(|(key, rec)| (key.path.clone(), (key, rec)))
+            .collect(),
+        Err(err) => {
+            warn!(
+                "could not read cache {}, rescanning everything: {}",
+                cache_path.to_string_lossy(),
+                err
+            );
+            HashMap::new()
+        }
     }
-    Ok(())
 }
 
-pub fn gen_lib(music_path: PathBuf, lib_path: PathBuf) -> anyhow::Result<()> {
+pub fn gen_lib(music_path: PathBuf, lib_path: PathBuf, opts: LibOpts) -> anyhow::Result<()> {
+    // TODO turn off symphonia logging
+    let cache_path = {
+        let mut file_name = lib_path.file_name().unwrap().to_owned();
+        file_name.push("_cache.bak");
+        lib_path.with_file_name(file_name)
+    };
+    let jobs = opts
+        .jobs
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+    let mut cache = if opts.rescan {
+        HashMap::new()
+    } else {
+        load_cache(&cache_path)
+    };
+
+    let templates = opts
+        .templates
+        .iter()
+        .map(|t| PathTemplate::parse(t))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut files = collect_files(&music_path, &opts)?;
+    // sorted so the library file stays stable between runs
+    files.sort();
+
+    let mut entries: Vec<(PathBuf, CacheKey, Option<Cached>)> = Vec::new();
+    for path in files {
+        match CacheKey::new(&path) {
+            Ok(key) => {
+                let cached = cache.remove(&key.path);
+                entries.push((path, key, cached));
+            }
+            Err(err) => warn!("{}: {}", path.to_string_lossy(), err),
+        }
+    }
+    let to_probe: Vec<&Path> = entries
+        .iter()
+        .filter(|(_, key, cached)| !cached.as_ref().is_some_and(|(c_key, _)| c_key == key))
+        .map(|(path, _, _)| path.as_path())
+        .collect();
+    info!("probing {} files with {} workers", to_probe.len(), jobs);
+    let mut probed = probe_all(&to_probe, jobs).into_iter();
+
     let mut wtr = csv::Writer::from_path(&lib_path)?;
-    write_all_metadata(&music_path, &mut wtr)?;
+    let mut cache_wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_path(&cache_path)?;
+    let (mut added, mut changed, mut unchanged) = (0, 0, 0);
+    for (path, key, cached) in entries {
+        let was_cached = cached.is_some();
+        let mut rec = match cached {
+            Some((cached_key, rec)) if cached_key == key => {
+                unchanged += 1;
+                rec
+            }
+            _ => match probed.next().unwrap() {
+                Ok(rec) => {
+                    if was_cached {
+                        changed += 1;
+                    } else {
+                        added += 1;
+                    }
+                    rec
+                }
+                Err(err) => {
+                    warn!("{}: {}", path.to_string_lossy(), err);
+                    continue;
+                }
+            },
+        };
+        rec.path = path
+            .strip_prefix(&music_path)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .into_owned();
+        // the cache keeps the tags as they are in the file, so changing templates takes effect
+        cache_wtr.serialize((key, &rec))?;
+        if let Some(fields) = templates.iter().find_map(|t| t.match_path(&rec.path)) {
+            fill_from_path(&mut rec, fields);
+        }
+        if rec.artists.is_empty() {
+            rec.artists.push(rec.artist.to_owned());
+        }
+        rec.artists = split_artists(&rec.artists, &opts.artist_delims);
+        if rec.name.trim().is_empty() {
+            warn!("{}: no track title tag", path.to_string_lossy());
+        }
+        wtr.serialize(&rec)?;
+    }
+    wtr.flush()?;
+    cache_wtr.flush()?;
+
+    info!(
+        "{} added, {} changed, {} removed, {} unchanged",
+        added,
+        changed,
+        cache.len(),
+        unchanged
+    );
     Ok(())
 }
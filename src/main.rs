@@ -1,26 +1,41 @@
-use clap::{command, Parser, Subcommand};
-use lib_gen::gen_lib;
-use log::{error, info, warn};
+use clap::{Parser, Subcommand};
+use config::{path_or_config, Config};
+use lib_gen::{gen_lib, LibOpts};
+use log::{error, info};
+use map::{MapOpts, RemapOpts};
+use retry::{with_retry, Retry};
+use review::ReviewOpts;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use spotify::{get_all_playlist_tracks, get_authc_sp, get_cred_sp, search_str};
-use spotify_rs::model::track::Track;
+use spotify::{get_all_playlist_tracks, get_authc_sp, get_search_sp, reauth_authc_sp, AuthcSp};
 use std::{
     fmt::Display,
     io::{self, stdin, stdout, Write},
     path::PathBuf,
-    time::Duration,
+    time::{SystemTime, UNIX_EPOCH},
 };
-use tokio::time::sleep;
 
+mod config;
 mod lib_gen;
 mod map;
+mod matching;
+mod query;
+mod retry;
+mod review;
+mod search_cache;
 mod spotify;
+mod tui;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// config file to use instead of config.toml in your config directory
+    #[arg(long, global = true, value_name = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// section of the config file to use on top of its defaults
+    #[arg(long, global = true)]
+    profile: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -28,39 +43,129 @@ enum Commands {
     Lib {
         /// path to your music files
         #[arg(value_name = "MUSIC_DIR")]
-        music_path: PathBuf,
+        music_path: Option<PathBuf>,
         /// .csv file that will contain songs from your library
         #[arg(value_name = "LIBRARY_FILE")]
-        lib_path: PathBuf,
+        lib_path: Option<PathBuf>,
+        #[command(flatten)]
+        opts: LibOpts,
     },
     Map {
         /// .csv file containing songs from your library
         #[arg(value_name = "LIBRARY_FILE")]
-        lib_path: PathBuf,
+        lib_path: Option<PathBuf>,
         /// .csv file containing mappings from songs to spotify songs
         #[arg(value_name = "MAP_FILE")]
-        map_path: PathBuf,
+        map_path: Option<PathBuf>,
+        #[command(flatten)]
+        opts: MapOpts,
+    },
+    /// search again for the tracks in the map that were "Not found"
+    Remap {
+        /// .csv file containing mappings from songs to spotify songs
+        #[arg(value_name = "MAP_FILE")]
+        map_path: Option<PathBuf>,
+        #[command(flatten)]
+        opts: RemapOpts,
+    },
+    /// walk through the tracks queued by map --non-interactive
+    Review {
+        /// .csv file containing mappings from songs to spotify songs
+        #[arg(value_name = "MAP_FILE")]
+        map_path: Option<PathBuf>,
+        #[command(flatten)]
+        opts: ReviewOpts,
     },
     Check {
         /// .csv file containing mappings from songs to spotify songs
         #[arg(value_name = "MAP_FILE")]
-        map_path: PathBuf,
+        map_path: Option<PathBuf>,
     },
     Upload {
         /// .csv file containing mappings from songs to spotify songs
         #[arg(value_name = "MAP_FILE")]
-        map_path: PathBuf,
+        map_path: Option<PathBuf>,
         /// id of the playlist you want to update
         #[arg(value_name = "PLAYLIST_ID")]
-        playlist_id: String,
+        playlist_id: Option<String>,
     },
+    /// forget the stored spotify login
+    Logout,
+}
+
+/// (De)serializes a list of artists as a single csv field, separated by "; ". A `;` or `\` in a
+/// name is escaped with `\`, so that names survive whatever `--artist-delim` split them
+mod artist_list {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(artists: &[String], s: S) -> Result<S::Ok, S::Error> {
+        let escaped: Vec<String> = artists
+            .iter()
+            .map(|a| a.replace('\\', "\\\\").replace(';', "\\;"))
+            .collect();
+        s.serialize_str(&escaped.join("; "))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+        let joined = String::deserialize(d)?;
+        let mut artists = Vec::new();
+        let mut artist = String::new();
+        let mut chars = joined.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(escaped @ (';' | '\\')) => artist.push(escaped),
+                    // not something we escape, so kept as it is
+                    Some(other) => artist.extend(['\\', other]),
+                    None => artist.push('\\'),
+                },
+                ';' => artists.push(std::mem::take(&mut artist)),
+                _ => artist.push(c),
+            }
+        }
+        artists.push(artist);
+        Ok(artists
+            .into_iter()
+            .map(|a| a.trim().to_owned())
+            .filter(|a| !a.is_empty())
+            .collect())
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 struct LibRec {
     name: String,
     album: String,
     artist: String,
+    #[serde(default)]
+    duration_ms: Option<u32>,
+    #[serde(default)]
+    track_number: Option<u32>,
+    #[serde(default)]
+    disc_number: Option<u32>,
+    #[serde(default)]
+    album_artist: String,
+    #[serde(default)]
+    year: Option<u32>,
+    #[serde(default)]
+    genre: String,
+    #[serde(default)]
+    isrc: String,
+    /// path of the file this came from, relative to MUSIC_DIR
+    #[serde(default)]
+    path: String,
+    /// hash of the audio, survives retagging and renaming
+    #[serde(default)]
+    track_key: String,
+    /// musicbrainz recording id, if the file is tagged with one
+    #[serde(default)]
+    mbid: String,
+    /// template fields that were filled in from the path because the tag was missing
+    #[serde(default)]
+    inferred: String,
+    /// every artist credited on the track, split out of the artist tags
+    #[serde(default, with = "artist_list")]
+    artists: Vec<String>,
 }
 
 impl Display for LibRec {
@@ -69,10 +174,36 @@ impl Display for LibRec {
             f,
             "Name: {}\nAlbum: {}\nArtist: {}",
             self.name, self.album, self.artist
-        )
+        )?;
+        if !self.album_artist.is_empty() && self.album_artist != self.artist {
+            write!(f, "\nAlbum artist: {}", self.album_artist)?;
+        }
+        if let Some(year) = self.year {
+            write!(f, "\nYear: {}", year)?;
+        }
+        if let Some(duration_ms) = self.duration_ms {
+            write!(f, "\nDuration: {}", fmt_duration(duration_ms))?;
+        }
+        if !self.path.is_empty() {
+            write!(f, "\nPath: {}", self.path)?;
+        }
+        Ok(())
     }
 }
 
+fn fmt_duration(duration_ms: u32) -> String {
+    let secs = duration_ms / 1000;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Seconds since the unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 impl LibRec {
     fn to_map_record(&self, sp_id: &str) -> MapRec {
         MapRec {
@@ -80,36 +211,104 @@ impl LibRec {
             album: self.album.to_owned(),
             artist: self.artist.to_owned(),
             sp_id: sp_id.to_owned(),
+            duration_ms: self.duration_ms,
+            track_number: self.track_number,
+            disc_number: self.disc_number,
+            album_artist: self.album_artist.to_owned(),
+            year: self.year,
+            genre: self.genre.to_owned(),
+            isrc: self.isrc.to_owned(),
+            path: self.path.to_owned(),
+            track_key: self.track_key.to_owned(),
+            mbid: self.mbid.to_owned(),
+            artists: self.artists.to_owned(),
+            searched_at: Some(unix_time()),
         }
     }
 
-    fn matches_track(&self, tr: &Track) -> bool {
-        return self.name.trim().to_lowercase() == tr.name.trim().to_lowercase()
-            && self.album.trim().to_lowercase() == tr.album.name.trim().to_lowercase()
-            && tr
-                .artists
-                .iter()
-                .any(|at| at.name.trim().to_lowercase() == self.artist.trim().to_lowercase());
-    }
-
-    // TODO double check that colon searching actually works
-    fn search_str(&self) -> String {
-        search_str("", &self.name, &self.album, &self.artist)
+    /// The split up artists, or the artist tag for libraries made before they were split
+    fn artist_list(&self) -> Vec<&str> {
+        if self.artists.is_empty() {
+            vec![self.artist.as_str()]
+        } else {
+            self.artists.iter().map(|a| a.as_str()).collect()
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 struct MapRec {
     name: String,
     album: String,
     artist: String,
     sp_id: String,
+    #[serde(default)]
+    duration_ms: Option<u32>,
+    #[serde(default)]
+    track_number: Option<u32>,
+    #[serde(default)]
+    disc_number: Option<u32>,
+    #[serde(default)]
+    album_artist: String,
+    #[serde(default)]
+    year: Option<u32>,
+    #[serde(default)]
+    genre: String,
+    #[serde(default)]
+    isrc: String,
+    #[serde(default)]
+    path: String,
+    #[serde(default)]
+    track_key: String,
+    #[serde(default)]
+    mbid: String,
+    #[serde(default, with = "artist_list")]
+    artists: Vec<String>,
+    /// when spotify was last searched for this track, in seconds since the unix epoch. Empty for
+    /// maps made before this was recorded
+    #[serde(default)]
+    searched_at: Option<u64>,
 }
 
 impl MapRec {
+    /// The library record this was made from, as far as the map has kept it
+    fn to_lib_record(&self) -> LibRec {
+        LibRec {
+            name: self.name.to_owned(),
+            album: self.album.to_owned(),
+            artist: self.artist.to_owned(),
+            duration_ms: self.duration_ms,
+            track_number: self.track_number,
+            disc_number: self.disc_number,
+            album_artist: self.album_artist.to_owned(),
+            year: self.year,
+            genre: self.genre.to_owned(),
+            isrc: self.isrc.to_owned(),
+            path: self.path.to_owned(),
+            track_key: self.track_key.to_owned(),
+            mbid: self.mbid.to_owned(),
+            inferred: String::new(),
+            artists: self.artists.to_owned(),
+        }
+    }
+
     fn matches(&self, lib_r: &LibRec) -> bool {
         self.name == lib_r.name && self.album == lib_r.album && self.artist == lib_r.artist
     }
+
+    /// Whether this is the same file as `lib_r`, even if its tags or location have changed. The
+    /// audio hash and musicbrainz id are compared where both records have them, the path only
+    /// when neither can be, as a different file can take the place of the old one
+    fn same_track(&self, lib_r: &LibRec) -> bool {
+        let compare = |a: &str, b: &str| (!a.is_empty() && !b.is_empty()).then(|| a == b);
+        match (
+            compare(&self.track_key, &lib_r.track_key),
+            compare(&self.mbid, &lib_r.mbid),
+        ) {
+            (None, None) => !self.path.is_empty() && self.path == lib_r.path,
+            (hash, mbid) => hash == Some(true) || mbid == Some(true),
+        }
+    }
 }
 
 fn ask<S: AsRef<str>>(question: &str, possible_answers: &[S]) -> anyhow::Result<String> {
@@ -137,39 +336,64 @@ fn collect_csv<T: DeserializeOwned>(path: &PathBuf, headers: bool) -> anyhow::Re
         .collect::<Result<Vec<T>, csv::Error>>()?)
 }
 
-async fn check(map_path: PathBuf) -> anyhow::Result<()> {
+async fn check(map_path: PathBuf, config: &Config) -> anyhow::Result<()> {
     let map: Vec<MapRec> = collect_csv(&map_path, true)?;
-    let mut cred_sp = get_cred_sp().await?;
+    let mut search_sp = get_search_sp(config).await?;
 
     for (ind, m_r) in map.iter().enumerate() {
         if m_r.sp_id == "Not found" {
             continue;
         }
-        loop {
-            match cred_sp.track(&m_r.sp_id).get().await {
-                Ok(_) => break,
-                Err(spotify_rs::Error::Spotify {
-                    status: 429, // rate limiting
-                    message: _,
-                }) => {
-                    sleep(Duration::from_secs(1)).await;
-                }
-                Err(_) => {
-                    error!(
-                        "line {}, \"{}\" has invalid id \"{}\"",
-                        ind + 1,
-                        m_r.name,
-                        m_r.sp_id
-                    );
-                    break;
-                }
+        match search_sp.track(&m_r.sp_id).await {
+            Ok(_) => {}
+            // spotify's answer for ids that don't exist or aren't ids at all
+            Err(spotify_rs::Error::Spotify {
+                status: 400 | 404, ..
+            }) => {
+                error!(
+                    "line {}, \"{}\" ({}) has invalid id \"{}\"",
+                    ind + 1,
+                    m_r.name,
+                    m_r.path,
+                    m_r.sp_id
+                );
             }
+            // anything else is still failing after being retried, or isn't about the id, e.g. a
+            // rejected login, so give up on checking
+            Err(err) => return Err(err.into()),
         }
     }
     Ok(())
 }
 
-async fn upload(map_path: PathBuf, playlist_id: &str) -> anyhow::Result<()> {
+/// Makes `call` with `retry`, logging in again once if the login was rejected or couldn't be
+/// refreshed
+async fn send_reauthed<T>(
+    authc_sp: &mut AuthcSp,
+    config: &Config,
+    retry: impl Fn() -> Retry,
+    mut call: impl AsyncFnMut(&mut AuthcSp) -> spotify_rs::SpotifyResult<T>,
+) -> anyhow::Result<T> {
+    let mut reauthed = false;
+    loop {
+        match with_retry!(retry(), call(authc_sp)) {
+            Err(spotify_rs::Error::Spotify {
+                status: 401, // token rejected
+                message: _,
+            })
+            | Err(spotify_rs::Error::ExpiredToken)
+            | Err(spotify_rs::Error::RefreshUnavailable)
+                if !reauthed =>
+            {
+                reauth_authc_sp(authc_sp, config).await?;
+                reauthed = true;
+            }
+            res => return Ok(res?),
+        }
+    }
+}
+
+async fn upload(map_path: PathBuf, playlist_id: &str, config: &Config) -> anyhow::Result<()> {
     struct R {
         m_r: MapRec,
         in_pl: bool,
@@ -182,7 +406,7 @@ async fn upload(map_path: PathBuf, playlist_id: &str) -> anyhow::Result<()> {
     } else {
         Vec::new()
     };
-    let mut authc_sp = get_authc_sp().await?;
+    let mut authc_sp = get_authc_sp(config).await?;
 
     let playlist = get_all_playlist_tracks(&mut authc_sp, playlist_id).await?;
 
@@ -234,41 +458,35 @@ async fn upload(map_path: PathBuf, playlist_id: &str) -> anyhow::Result<()> {
     const SEND_LIM: usize = 100;
     for chunk in to_remove.chunks(SEND_LIM) {
         info!("Removing...");
-        loop {
-            let res = authc_sp
-                .remove_playlist_items(playlist_id, chunk)
-                .send()
-                .await;
-            if let Err(spotify_rs::Error::Spotify {
-                status: 429, // rate limiting
-                message: _,
-            }) = res
-            {
-                sleep(Duration::from_secs(1)).await;
-            } else {
-                res?;
-                break;
-            }
-        }
+        send_reauthed(
+            &mut authc_sp,
+            config,
+            || Retry::new("Removing from the playlist"),
+            async |authc_sp| {
+                authc_sp
+                    .remove_playlist_items(playlist_id, chunk)
+                    .send()
+                    .await
+            },
+        )
+        .await?;
     }
     for chunk in to_add.chunks(SEND_LIM) {
         info!("Adding...");
-        loop {
-            let res = authc_sp
-                .add_items_to_playlist(playlist_id, chunk)
-                .send()
-                .await;
-            if let Err(spotify_rs::Error::Spotify {
-                status: 429, // rate limiting
-                message: _,
-            }) = res
-            {
-                sleep(Duration::from_secs(1)).await;
-            } else {
-                res?;
-                break;
-            }
-        }
+        // not retried on errors that leave us unsure whether the tracks were added, as adding
+        // them twice would duplicate them in the playlist
+        send_reauthed(
+            &mut authc_sp,
+            config,
+            || Retry::rate_limits_only("Adding to the playlist"),
+            async |authc_sp| {
+                authc_sp
+                    .add_items_to_playlist(playlist_id, chunk)
+                    .send()
+                    .await
+            },
+        )
+        .await?;
     }
 
     info!("Upload complete");
@@ -280,22 +498,58 @@ async fn upload(map_path: PathBuf, playlist_id: &str) -> anyhow::Result<()> {
 async fn main() -> anyhow::Result<()> {
     // TODO make errors not look like ass
     // TODO maybe use console, dialoguer and indicatif crates
-    // TODO prevent rate limiting errors from breaking things
-    // TODO refresh token if it expires
 
-    colog::init();
+    tui::init_logging();
     let cli = Cli::parse();
-    match cli.command {
+    let config = config::load(cli.config, cli.profile)?;
+    let map_path = |arg| path_or_config(arg, &config.map_path, "MAP_FILE");
+    let res = match cli.command {
         Commands::Lib {
             music_path,
             lib_path,
-        } => gen_lib(music_path, lib_path),
-        Commands::Map { lib_path, map_path } => map::map(lib_path, map_path).await,
-        Commands::Check { map_path } => check(map_path).await,
+            opts,
+        } => gen_lib(
+            path_or_config(music_path, &config.music_path, "MUSIC_DIR")?,
+            path_or_config(lib_path, &config.lib_path, "LIBRARY_FILE")?,
+            opts,
+        ),
+        Commands::Map {
+            lib_path,
+            map_path: map_arg,
+            opts,
+        } => {
+            map::map(
+                path_or_config(lib_path, &config.lib_path, "LIBRARY_FILE")?,
+                map_path(map_arg)?,
+                opts,
+                &config,
+            )
+            .await
+        }
+        Commands::Remap {
+            map_path: map_arg,
+            opts,
+        } => map::remap(map_path(map_arg)?, opts, &config).await,
+        Commands::Review {
+            map_path: map_arg,
+            opts,
+        } => review::review(map_path(map_arg)?, opts, &config).await,
+        Commands::Check { map_path: map_arg } => check(map_path(map_arg)?, &config).await,
         Commands::Upload {
-            map_path,
+            map_path: map_arg,
             playlist_id,
-        } => upload(map_path, &playlist_id).await,
-    }?;
+        } => {
+            let playlist_id =
+                playlist_id
+                    .or_else(|| config.playlist_id.clone())
+                    .ok_or(anyhow::anyhow!(
+                        "No PLAYLIST_ID given and none set in the config file"
+                    ))?;
+            upload(map_path(map_arg)?, &playlist_id, &config).await
+        }
+        Commands::Logout => spotify::logout(&config),
+    };
+    retry::report_waited();
+    res?;
     Ok(())
 }
@@ -1,36 +1,45 @@
 use std::{
+    collections::HashMap,
     fs::{self, File},
     io::{self, Seek, Write},
-    path::PathBuf,
-    time::Duration,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
-use anyhow::{anyhow, Context};
+use anyhow::anyhow;
+use clap::Args;
+use futures::{stream, StreamExt};
 use log::{info, warn};
-use spotify_rs::{
-    auth::{NoVerifier, Token},
-    client::Client,
-    model::{search::Item, track::Track},
-    ClientCredsFlow,
-};
-use tokio::time::sleep;
+use tokio::sync::mpsc;
 
 use crate::{
     ask, collect_csv,
-    spotify::{get_cred_sp, print_track, search_str},
-    LibRec, MapRec,
+    config::Config,
+    fmt_duration, matching,
+    query::{parse_ladder, QueryTemplate},
+    review::ReviewQueue,
+    search_cache::{SearchCache, DEFAULT_TTL_DAYS},
+    spotify::{get_search_sps, parse_track_id, print_track, SearchSp, SharedSearchSp, SpTrack},
+    tui::Tui,
+    unix_time, LibRec, MapRec,
 };
 
 struct ProgMap {
     recs: Vec<MapRec>,
     writer: csv::Writer<File>,
+    prog_path: PathBuf,
     lib_name: String,
 }
 
 impl ProgMap {
-    fn new(prog_path: &PathBuf, lib_path: &PathBuf) -> anyhow::Result<Self> {
+    fn new(prog_path: &PathBuf, lib_path: &Path, interactive: bool) -> anyhow::Result<Self> {
         let prog_file = if prog_path.exists() {
-            let answer = ask("In progress search detected, would you like to continue from this backup? (if not, this will overwrite the backup file)[Y/n]:", &["y", "n", ""])?;
+            let answer = if interactive {
+                ask("In progress search detected, would you like to continue from this backup? (if not, this will overwrite the backup file)[Y/n]:", &["y", "n", ""])?
+            } else {
+                info!("In progress search detected, continuing from backup");
+                String::new()
+            };
             if answer == "n" {
                 File::create(prog_path)?
             } else {
@@ -46,6 +55,7 @@ impl ProgMap {
             writer: csv::WriterBuilder::new()
                 .has_headers(false)
                 .from_writer(prog_file),
+            prog_path: prog_path.clone(),
             lib_name: lib_path.file_name().unwrap().to_string_lossy().into_owned(),
         })
     }
@@ -66,6 +76,16 @@ impl ProgMap {
                 );
                 map_rec
             }
+            Prog::IsrcMatch(map_rec) => {
+                info!(
+                    "line {}, \"{}\" matched by ISRC {}, added with id: {}",
+                    self.index() + 1,
+                    map_rec.name,
+                    map_rec.isrc,
+                    map_rec.sp_id,
+                );
+                map_rec
+            }
             Prog::ChosenSearch(map_rec) => {
                 info!(
                     "line {}, \"{}\" added with id: {}",
@@ -85,12 +105,39 @@ impl ProgMap {
             }
             Prog::NotFoundSearch(lib_rec) => {
                 warn!(
-                    "line {}, \"{}\" not found by spotify search",
+                    "line {}, \"{}\" ({}) not found by spotify search",
                     self.index() + 1,
-                    lib_rec.name
+                    lib_rec.name,
+                    lib_rec.path,
                 );
                 lib_rec.to_map_record("Not found")
             }
+            Prog::Retagged(map_rec, old_name) => {
+                info!(
+                    "line {}, \"{}\" was retagged as \"{}\", keeping id: {}",
+                    self.index() + 1,
+                    old_name,
+                    map_rec.name,
+                    map_rec.sp_id,
+                );
+                map_rec
+            }
+            Prog::Skipped(lib_rec) => {
+                info!(
+                    "line {}, \"{}\" skipped for now, it will be asked about next time",
+                    self.index() + 1,
+                    lib_rec.name,
+                );
+                MapRec::default()
+            }
+            Prog::Queued(lib_rec) => {
+                info!(
+                    "line {}, \"{}\" queued for review",
+                    self.index() + 1,
+                    lib_rec.name,
+                );
+                MapRec::default()
+            }
             Prog::PresentInMap(lib_rec) => {
                 info!(
                     "line {}, \"{}\" already present in map",
@@ -99,11 +146,12 @@ impl ProgMap {
                 );
                 MapRec::default()
             }
-            Prog::MissingName => {
+            Prog::MissingName(lib_rec) => {
                 warn!(
-                    "line {} in {} has empty Name field, skipping...",
+                    "line {} in {} ({}) has empty Name field, skipping...",
                     self.index() + 1,
                     self.lib_name,
+                    lib_rec.path,
                 );
                 MapRec::default()
             }
@@ -114,85 +162,753 @@ impl ProgMap {
         Ok(())
     }
 
+    /// Forgets every record from line `index` on, and rewrites the backup without them
+    fn undo(&mut self, index: usize) -> anyhow::Result<()> {
+        self.recs.truncate(index);
+        self.writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_path(&self.prog_path)?;
+        for rec in &self.recs {
+            self.writer.serialize(rec)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
     fn recs(self) -> Vec<MapRec> {
         self.recs
     }
 }
 
+const DEFAULT_THRESHOLD: f64 = 0.9;
+const DEFAULT_DURATION_TOLERANCE: u32 = 3;
+const DEFAULT_MAX_DURATION_DELTA: u32 = 10;
+
+#[derive(Args)]
+pub struct MatchOpts {
+    /// match confidence from 0 to 1 above which a search result is chosen without asking,
+    /// defaults to 0.9
+    #[arg(long)]
+    threshold: Option<f64>,
+    /// seconds a search result's duration can differ from the file's without being penalised,
+    /// defaults to 3
+    #[arg(long, value_name = "SECONDS")]
+    duration_tolerance: Option<u32>,
+    /// seconds a search result's duration can differ from the file's and still be chosen without
+    /// asking, defaults to 10
+    #[arg(long, value_name = "SECONDS")]
+    max_duration_delta: Option<u32>,
+}
+
+impl MatchOpts {
+    /// Fills in whatever wasn't given on the command line from the config
+    pub fn apply_config(&mut self, config: &Config) {
+        self.threshold = self.threshold.or(config.threshold);
+        self.duration_tolerance = self.duration_tolerance.or(config.duration_tolerance);
+        self.max_duration_delta = self.max_duration_delta.or(config.max_duration_delta);
+    }
+
+    /// Whether `tr` is too much longer or shorter than the file to be chosen without asking, e.g.
+    /// a live version or radio edit
+    fn duration_far_off(&self, lib_r: &LibRec, tr: &SpTrack) -> bool {
+        let max_delta_ms = self
+            .max_duration_delta
+            .unwrap_or(DEFAULT_MAX_DURATION_DELTA)
+            * 1000;
+        lib_r
+            .duration_ms
+            .is_some_and(|duration_ms| duration_ms.abs_diff(tr.duration_ms) > max_delta_ms)
+    }
+
+    fn threshold(&self) -> f64 {
+        self.threshold.unwrap_or(DEFAULT_THRESHOLD)
+    }
+
+    /// Sorts `tracks` best match first, alongside their scores
+    pub fn score_tracks(&self, lib_r: &LibRec, tracks: Vec<SpTrack>) -> (Vec<f64>, Vec<SpTrack>) {
+        let tolerance_ms = self
+            .duration_tolerance
+            .unwrap_or(DEFAULT_DURATION_TOLERANCE)
+            * 1000;
+        let mut scored: Vec<(f64, SpTrack)> = tracks
+            .into_iter()
+            .map(|tr| (matching::score(lib_r, &tr, tolerance_ms), tr))
+            .collect();
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        scored.into_iter().unzip()
+    }
+}
+
+#[derive(Args)]
+pub struct MapOpts {
+    #[command(flatten)]
+    match_opts: MatchOpts,
+    /// never prompt, tracks without a confident match are written to a review file instead
+    #[arg(long)]
+    non_interactive: bool,
+    /// choose matches in a full screen terminal UI instead of at a prompt
+    #[arg(long, conflicts_with = "non_interactive")]
+    tui: bool,
+    /// where tracks are queued for the review subcommand, defaults to MAP_FILE_review.csv
+    #[arg(long, value_name = "REVIEW_FILE")]
+    review_path: Option<PathBuf>,
+    /// search query, e.g. "track:{name:strip} artist:{artist}", tried in order until enough results
+    /// are found. fields are name, album, artist, album_artist and year, and can be followed by
+    /// :strip to drop bracketed and version suffixes or :quote to search for an exact phrase
+    /// (can be repeated)
+    #[arg(long = "search", value_name = "TEMPLATE")]
+    search_ladder: Vec<String>,
+    /// number of searches to make at once
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+    /// search again instead of reusing results cached by previous runs
+    #[arg(long)]
+    refresh: bool,
+    /// days that search results are cached for, defaults to 30
+    #[arg(long, value_name = "DAYS")]
+    cache_ttl: Option<u64>,
+    /// backup of an in progress mapping, defaults to LIBRARY_FILE_progress.bak in the current
+    /// directory
+    #[arg(long, value_name = "PROGRESS_FILE")]
+    progress_path: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct RemapOpts {
+    #[command(flatten)]
+    match_opts: MatchOpts,
+    /// only search again for tracks with an artist containing this
+    #[arg(long)]
+    artist: Option<String>,
+    /// only search again for tracks with an album containing this
+    #[arg(long)]
+    album: Option<String>,
+    /// only search again for tracks last searched for more than this many days ago
+    #[arg(long, value_name = "DAYS")]
+    older_than: Option<u64>,
+    /// never prompt, tracks without a confident match stay "Not found"
+    #[arg(long)]
+    non_interactive: bool,
+    /// choose matches in a full screen terminal UI instead of at a prompt
+    #[arg(long, conflicts_with = "non_interactive")]
+    tui: bool,
+    /// search query tried in order until enough results are found, see map --search (can be
+    /// repeated)
+    #[arg(long = "search", value_name = "TEMPLATE")]
+    search_ladder: Vec<String>,
+    /// number of searches to make at once
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+}
+
+impl RemapOpts {
+    /// Whether `map_r` passes the filters
+    fn selects(&self, map_r: &MapRec) -> bool {
+        let contains =
+            |field: &str, filter: &str| field.to_lowercase().contains(&filter.to_lowercase());
+        if let Some(artist) = &self.artist {
+            if !contains(&map_r.artist, artist)
+                && !map_r.artists.iter().any(|a| contains(a, artist))
+            {
+                return false;
+            }
+        }
+        if let Some(album) = &self.album {
+            if !contains(&map_r.album, album) {
+                return false;
+            }
+        }
+        // tracks from before searches were dated count as old enough
+        match (self.older_than, map_r.searched_at) {
+            (Some(days), Some(searched_at)) => {
+                unix_time().saturating_sub(searched_at) > days * 24 * 60 * 60
+            }
+            _ => true,
+        }
+    }
+}
+
+pub fn default_review_path(map_path: &Path) -> PathBuf {
+    let mut file_name = map_path.file_name().unwrap().to_owned();
+    file_name.push("_review.csv");
+    map_path.with_file_name(file_name)
+}
+
+pub enum Ans {
+    Chosen(String),
+    NotFound,
+    /// leave the track out of the map for now
+    Skip,
+    /// go back to the previous decision
+    Undo,
+    /// stop, keeping whatever has been decided so far
+    Quit,
+}
+
+/// Searches again for the chooser, when asked for more results or for a query of the user's own
+pub struct Searcher<'a> {
+    config: &'a Config,
+    /// shared with searching ahead, as a second login can invalidate the first
+    search_sp: SharedSearchSp,
+    cache: Arc<Mutex<SearchCache>>,
+    ladder: Arc<Vec<QueryTemplate>>,
+}
+
+impl<'a> Searcher<'a> {
+    pub fn new(
+        config: &'a Config,
+        search_sp: SharedSearchSp,
+        cache: Arc<Mutex<SearchCache>>,
+        ladder: Arc<Vec<QueryTemplate>>,
+    ) -> Self {
+        Self {
+            config,
+            search_sp,
+            cache,
+            ladder,
+        }
+    }
+
+    pub async fn tracks(&mut self, ids: &[&str]) -> anyhow::Result<Vec<SpTrack>> {
+        Ok(self.search_sp.lock().await.tracks(ids).await?)
+    }
+
+    /// None if spotify has no track with this id
+    pub async fn track(&mut self, id: &str) -> anyhow::Result<Option<SpTrack>> {
+        match self.search_sp.lock().await.track(id).await {
+            Ok(track) => Ok(Some(track)),
+            Err(spotify_rs::Error::Spotify {
+                status: 400 | 404, ..
+            }) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn ladder(&mut self, lib_r: &LibRec, limit: u32) -> anyhow::Result<Vec<SpTrack>> {
+        let mut search_sp = self.search_sp.lock().await;
+        let market = &self.config.market;
+        search_ladder(
+            lib_r,
+            &mut search_sp,
+            &self.cache,
+            market,
+            &self.ladder,
+            limit,
+        )
+        .await
+    }
+
+    async fn query(&mut self, query: &str, limit: u32) -> anyhow::Result<Vec<SpTrack>> {
+        let mut search_sp = self.search_sp.lock().await;
+        cached_search(
+            query,
+            &mut search_sp,
+            &self.cache,
+            &self.config.market,
+            limit,
+        )
+        .await
+    }
+}
+
+fn read_answer(prompt: &str) -> anyhow::Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_owned())
+}
+
+/// Asks for a track id, URI or share link until spotify knows the track and the user confirms it.
+/// None if nothing was entered
+async fn ask_manual_id(
+    lib_r: &LibRec,
+    searcher: &mut Searcher<'_>,
+) -> anyhow::Result<Option<String>> {
+    loop {
+        let answer = read_answer(
+            "Please manually enter the spotify id, URI or link (leave empty to go back): ",
+        )?;
+        if answer.is_empty() {
+            return Ok(None);
+        }
+        let id = match parse_track_id(&answer) {
+            Ok(id) => id,
+            Err(err) => {
+                println!("{}", err);
+                continue;
+            }
+        };
+        let Some(track) = searcher.track(&id).await? else {
+            println!("Spotify has no track with id {}", id);
+            continue;
+        };
+        println!("\n= Entered track =");
+        print_track(&track, lib_r.duration_ms);
+        println!();
+        if ask("Use this track? [Y/n]: ", &["y", "n", ""])? != "n" {
+            return Ok(Some(track.id));
+        }
+    }
+}
+
+/// Search results being chosen from and their scores, best first until more are asked for
+pub struct Candidates {
+    pub tracks: Vec<SpTrack>,
+    pub scores: Vec<f64>,
+    /// a query typed in by the user, results are paged through from the ladder otherwise
+    query: Option<String>,
+    limit: u32,
+}
+
+impl Candidates {
+    pub fn new(tracks: Vec<SpTrack>, scores: Vec<f64>) -> Self {
+        Self {
+            tracks,
+            scores,
+            query: None,
+            limit: PAGE_SIZE,
+        }
+    }
+
+    /// Adds the next page of results after the ones already there, so their numbers don't change.
+    /// Returns how many were added
+    pub async fn more(
+        &mut self,
+        lib_r: &LibRec,
+        match_opts: &MatchOpts,
+        searcher: &mut Searcher<'_>,
+    ) -> anyhow::Result<usize> {
+        if self.limit >= MAX_LIMIT {
+            return Ok(0);
+        }
+        self.limit = (self.limit + PAGE_SIZE).min(MAX_LIMIT);
+        let found = match &self.query {
+            Some(query) => searcher.query(query, self.limit).await?,
+            None => searcher.ladder(lib_r, self.limit).await?,
+        };
+        let new: Vec<SpTrack> = found
+            .into_iter()
+            .filter(|tr| !self.tracks.iter().any(|shown| shown.id == tr.id))
+            .collect();
+        let added = new.len();
+        let (scores, new) = match_opts.score_tracks(lib_r, new);
+        self.tracks.extend(new);
+        self.scores.extend(scores);
+        Ok(added)
+    }
+
+    /// Replaces the results with those for `query`, unless it finds nothing. Returns whether it
+    /// found anything
+    pub async fn search(
+        &mut self,
+        query: &str,
+        lib_r: &LibRec,
+        match_opts: &MatchOpts,
+        searcher: &mut Searcher<'_>,
+    ) -> anyhow::Result<bool> {
+        let found = searcher.query(query, PAGE_SIZE).await?;
+        if found.is_empty() {
+            return Ok(false);
+        }
+        (self.scores, self.tracks) = match_opts.score_tracks(lib_r, found);
+        self.query = Some(query.to_owned());
+        self.limit = PAGE_SIZE;
+        Ok(true)
+    }
+
+    /// Puts `track` first, e.g. one entered by id
+    pub fn push_front(&mut self, lib_r: &LibRec, match_opts: &MatchOpts, track: SpTrack) {
+        self.tracks.retain(|tr| tr.id != track.id);
+        let (scores, tracks) = match_opts.score_tracks(lib_r, vec![track]);
+        self.tracks.splice(0..0, tracks);
+        self.scores.splice(0..0, scores);
+    }
+}
+
+fn print_results(lib_r: &LibRec, tracks: &[SpTrack], scores: &[f64], first: usize) {
+    for (i, item) in tracks.iter().enumerate().skip(first) {
+        println!(
+            "= Search result {} ({:.0}% match) =",
+            i + 1,
+            scores[i] * 100.0
+        );
+        print_track(item, lib_r.duration_ms);
+        println!();
+    }
+}
+
+/// Where matches are chosen, at the prompt or in the terminal UI
+pub enum Ui {
+    Prompt,
+    /// started when there is first something to choose
+    Tui(Option<Tui>),
+}
+
+impl Ui {
+    pub fn new(tui: bool) -> Self {
+        if tui {
+            Ui::Tui(None)
+        } else {
+            Ui::Prompt
+        }
+    }
+
+    /// See `choose_track`
+    pub async fn choose_track(
+        &mut self,
+        lib_r: &LibRec,
+        tracks: Vec<SpTrack>,
+        scores: Vec<f64>,
+        match_opts: &MatchOpts,
+        searcher: &mut Searcher<'_>,
+        can_undo: bool,
+    ) -> anyhow::Result<Ans> {
+        match self {
+            Ui::Prompt => choose_track(lib_r, tracks, scores, match_opts, searcher, can_undo).await,
+            Ui::Tui(tui) => {
+                if tui.is_none() {
+                    *tui = Some(Tui::new()?);
+                }
+                let tui = tui.as_mut().unwrap();
+                tui.choose_track(lib_r, tracks, scores, match_opts, searcher, can_undo)
+                    .await
+            }
+        }
+    }
+
+    /// Gives the terminal back, if the terminal UI is up
+    pub fn close(&mut self) {
+        if let Ui::Tui(tui) = self {
+            *tui = None;
+        }
+    }
+}
+
+const CHOOSER_HELP: &str = "\
+#  use that search result
+s  enter a spotify id, URI or link
+n  not on spotify
+m  show more results
+r  search again with your own query
+u  undo the previous decision
+k  skip this track for now, it will be asked about next time
+q  quit, keeping progress so far";
+
+/// Shows `lib_r` and its search results, and asks which one is the right one. `can_undo` is
+/// whether there is a previous decision to go back to
+pub async fn choose_track(
+    lib_r: &LibRec,
+    tracks: Vec<SpTrack>,
+    scores: Vec<f64>,
+    match_opts: &MatchOpts,
+    searcher: &mut Searcher<'_>,
+    can_undo: bool,
+) -> anyhow::Result<Ans> {
+    let mut candidates = Candidates::new(tracks, scores);
+    println!("=== Track to match ==============================");
+    println!("{lib_r}\n");
+    println!("=== Search results ====================");
+    print_results(lib_r, &candidates.tracks, &candidates.scores, 0);
+    loop {
+        let answer = read_answer("Pick a track to match (#/s/n/m/r/u/k/q, ? for help): ")?;
+        match answer.to_lowercase().as_str() {
+            "n" => return Ok(Ans::NotFound),
+            "s" => {
+                if let Some(id) = ask_manual_id(lib_r, searcher).await? {
+                    return Ok(Ans::Chosen(id));
+                }
+            }
+            "m" => {
+                let first = candidates.tracks.len();
+                if candidates.more(lib_r, match_opts, searcher).await? == 0 {
+                    println!("No more results");
+                    continue;
+                }
+                print_results(lib_r, &candidates.tracks, &candidates.scores, first);
+            }
+            "r" => {
+                let answer = read_answer("Search for (leave empty to go back): ")?;
+                if answer.is_empty() {
+                    continue;
+                }
+                if !candidates
+                    .search(&answer, lib_r, match_opts, searcher)
+                    .await?
+                {
+                    println!("Nothing found for \"{}\"", answer);
+                    continue;
+                }
+                println!("=== Search results ====================");
+                print_results(lib_r, &candidates.tracks, &candidates.scores, 0);
+            }
+            "u" if can_undo => return Ok(Ans::Undo),
+            "u" => println!("Nothing to undo"),
+            "k" => return Ok(Ans::Skip),
+            "q" => return Ok(Ans::Quit),
+            "?" => println!("{}", CHOOSER_HELP),
+            answer => {
+                if let Ok(i) = answer.parse::<usize>() {
+                    if i > 0 && i < candidates.tracks.len() + 1 {
+                        return Ok(Ans::Chosen(candidates.tracks[i - 1].id.clone()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// By artist, then album, then name, the order the map file is written in
+fn sort_map(map: &mut [MapRec]) {
+    map.sort_by_key(|m_r| m_r.name.clone());
+    map.sort_by_key(|m_r| m_r.album.clone());
+    map.sort_by_key(|m_r| m_r.artist.clone());
+}
+
+/// Writes `map` sorted to `map_path`, through a temporary file so it is never half written
+pub fn write_map(map_path: &Path, map: &mut [MapRec]) -> anyhow::Result<()> {
+    sort_map(map);
+
+    let temp_map_path = {
+        let mut file_name = map_path.file_name().unwrap().to_owned();
+        file_name.push(".tmp");
+        PathBuf::from(file_name)
+    };
+    if temp_map_path.exists() {
+        return Err(anyhow!(format!(
+            "Path {} already exists",
+            temp_map_path.to_string_lossy()
+        )));
+    }
+    let mut wtr = csv::Writer::from_path(&temp_map_path)?;
+    for map_r in map.iter() {
+        wtr.serialize(map_r)?;
+    }
+    wtr.flush()?;
+    fs::rename(temp_map_path, map_path)?;
+    Ok(())
+}
+
 #[derive(Debug)]
 enum Prog {
     AutomaticallyChosenSearch(MapRec),
     ChosenSearch(MapRec),
     RejectedSearch(LibRec),
     NotFoundSearch(LibRec),
+    /// search result with the same ISRC as the file
+    IsrcMatch(MapRec),
+    /// new record carrying over the id of a map entry for the same track, and that entry's old name
+    Retagged(MapRec, String),
+    /// ambiguous search results that were written to the review file
+    Queued(LibRec),
+    /// left out of the map until the next run
+    Skipped(LibRec),
     PresentInMap(LibRec),
-    MissingName,
+    MissingName(LibRec),
+}
+
+async fn cached_search(
+    query: &str,
+    search_sp: &mut SearchSp,
+    cache: &Mutex<SearchCache>,
+    market: &str,
+    limit: u32,
+) -> anyhow::Result<Vec<SpTrack>> {
+    let cached = cache
+        .lock()
+        .unwrap()
+        .get(query, market, limit)
+        .map(<[SpTrack]>::to_vec);
+    if let Some(cached) = cached {
+        return Ok(cached);
+    }
+    let found = search_sp.search(query, market, limit).await?;
+    cache
+        .lock()
+        .unwrap()
+        .insert(query, market, limit, found.clone())?;
+    Ok(found)
 }
 
-// TODO if search returns no results, gradually widen the search parameters ideally until you have 5 results
+/// Search results shown at once
+const PAGE_SIZE: u32 = 5;
+/// Most results spotify gives for one search
+const MAX_LIMIT: u32 = 50;
+
+/// Searches by ISRC if the file has one, falling back to the search ladder if that finds nothing
 async fn search_tracks(
     lib_r: &LibRec,
-    cred_sp: &mut Client<Token, ClientCredsFlow, NoVerifier>,
-) -> anyhow::Result<Vec<Track>> {
-    let mut res = vec![];
-    let mut search_lvl = 0;
-    while res.len() < 5 && search_lvl <= 2 {
-        let search = match search_lvl {
-            0 => {
-                cred_sp
-                    .search(lib_r.search_str(), &[Item::Track])
-                    .market("GB")
-                    .limit(5)
-                    .get()
-                    .await
-            }
-            1 => {
-                cred_sp
-                    .search(
-                        search_str(&lib_r.name, "", &lib_r.album, ""),
-                        &[Item::Track],
-                    )
-                    .market("GB")
-                    .limit(5)
-                    .get()
-                    .await
-            }
-            2 => {
-                let mut s_str = String::new();
-                s_str += &lib_r.name;
-                s_str += " ";
-                s_str += &lib_r.artist;
-                cred_sp
-                    .search(search_str(&s_str, "", "", ""), &[Item::Track])
-                    .market("GB")
-                    .limit(5)
-                    .get()
-                    .await
-            }
-            _ => unreachable!(),
-        };
-        if let Err(spotify_rs::Error::Spotify {
-            status: 429, // rate limiting
-            message: _,
-        }) = search
-        {
-            sleep(Duration::from_secs(1)).await;
+    search_sp: &mut SearchSp,
+    cache: &Mutex<SearchCache>,
+    market: &str,
+    ladder: &[QueryTemplate],
+) -> anyhow::Result<Vec<SpTrack>> {
+    let isrc = normalise_isrc(&lib_r.isrc);
+    if !isrc.is_empty() {
+        let query = format!("isrc:{}", isrc);
+        let res = cached_search(&query, search_sp, cache, market, PAGE_SIZE).await?;
+        if !res.is_empty() {
+            return Ok(res);
+        }
+    }
+    search_ladder(lib_r, search_sp, cache, market, ladder, PAGE_SIZE).await
+}
+
+/// Tries each query in the ladder in turn until there are `limit` distinct results
+async fn search_ladder(
+    lib_r: &LibRec,
+    search_sp: &mut SearchSp,
+    cache: &Mutex<SearchCache>,
+    market: &str,
+    ladder: &[QueryTemplate],
+    limit: u32,
+) -> anyhow::Result<Vec<SpTrack>> {
+    let mut res: Vec<SpTrack> = vec![];
+    let mut tried = vec![];
+    for template in ladder {
+        if res.len() >= limit as usize {
+            break;
+        }
+        let query = template.render(lib_r);
+        // templates can come out the same when a field is empty
+        if query.is_empty() || tried.contains(&query) {
             continue;
-        } else {
-            let mut new_res = search?
-                .tracks
-                .with_context(|| "spotify search failed")?
-                .items;
-            res.append(&mut new_res);
         }
-        search_lvl += 1;
+        for tr in cached_search(&query, search_sp, cache, market, limit).await? {
+            if !res.iter().any(|r_tr| r_tr.id == tr.id) {
+                res.push(tr);
+            }
+        }
+        tried.push(query);
     }
-    res.truncate(5);
+    res.truncate(limit as usize);
     Ok(res)
 }
 
-pub async fn map(lib_path: PathBuf, map_path: PathBuf) -> anyhow::Result<()> {
-    let mut cred_sp = get_cred_sp().await?;
+/// Searches for `to_search` concurrently, one search per client, ahead of the track being
+/// mapped. Results come back in the same order as `to_search` with the line they are for, and no
+/// more than a few get ahead.
+fn search_ahead(
+    to_search: Vec<(usize, LibRec)>,
+    search_sps: Vec<SharedSearchSp>,
+    cache: Arc<Mutex<SearchCache>>,
+    market: String,
+    ladder: Arc<Vec<QueryTemplate>>,
+) -> mpsc::Receiver<anyhow::Result<(usize, Vec<SpTrack>)>> {
+    let jobs = search_sps.len();
+    let (tx, rx) = mpsc::channel(jobs);
+    // runs on the runtime's worker threads, so it keeps going while map waits for input
+    tokio::spawn(async move {
+        // each search has its own client while running, as they can't be shared. The chooser
+        // can still borrow one between searches
+        let search_sps = Mutex::new(search_sps);
+        let mut results = stream::iter(to_search)
+            .map(|(index, lib_r)| {
+                let (search_sps, cache, market, ladder) = (&search_sps, &cache, &market, &ladder);
+                async move {
+                    let shared_sp = search_sps.lock().unwrap().pop().unwrap();
+                    let mut search_sp = shared_sp.lock().await;
+                    let res = search_tracks(&lib_r, &mut search_sp, cache, market, ladder).await;
+                    drop(search_sp);
+                    search_sps.lock().unwrap().push(shared_sp);
+                    res.map(|res| (index, res))
+                }
+            })
+            .buffered(jobs);
+        while let Some(res) = results.next().await {
+            if tx.send(res).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// What searching found for a track, and whether it's sure enough to be used without asking
+enum AutoChoice {
+    NotFound,
+    /// a search result with the same ISRC as the file
+    Isrc(String),
+    /// a search result scoring above the threshold
+    Confident(String),
+    /// the search results scored, best first, to be chosen from
+    Unsure(Vec<f64>, Vec<SpTrack>),
+}
+
+fn auto_choose(lib_r: &LibRec, search_results: Vec<SpTrack>, match_opts: &MatchOpts) -> AutoChoice {
+    if search_results.is_empty() {
+        return AutoChoice::NotFound;
+    }
+    if let Some(tr) = search_results
+        .iter()
+        .find(|tr| same_isrc(lib_r, tr) && !match_opts.duration_far_off(lib_r, tr))
+    {
+        return AutoChoice::Isrc(tr.id.clone());
+    }
+    let (scores, search_results) = match_opts.score_tracks(lib_r, search_results);
+    if scores[0] >= match_opts.threshold() {
+        if !match_opts.duration_far_off(lib_r, &search_results[0]) {
+            return AutoChoice::Confident(search_results[0].id.clone());
+        }
+        info!(
+            "\"{}\" not chosen automatically, its best match is {} long and the file is {}",
+            lib_r.name,
+            fmt_duration(search_results[0].duration_ms),
+            fmt_duration(lib_r.duration_ms.unwrap_or_default()),
+        );
+    }
+    AutoChoice::Unsure(scores, search_results)
+}
+
+/// Tags sometimes write ISRCs with dashes, e.g. "GB-AYE-69-00531"
+fn normalise_isrc(isrc: &str) -> String {
+    isrc.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+fn same_isrc(lib_r: &LibRec, tr: &SpTrack) -> bool {
+    let isrc = normalise_isrc(&lib_r.isrc);
+    !isrc.is_empty() && tr.isrc.as_deref().map(normalise_isrc) == Some(isrc)
+}
+
+/// What to do with `lib_r` if it can be decided without searching
+fn precheck(lib_r: &LibRec, lib: &[LibRec], map: &[MapRec]) -> Option<Prog> {
+    if lib_r.name.trim().is_empty() {
+        return Some(Prog::MissingName(lib_r.clone()));
+    }
+    // if lib_r already present in map
+    if map.iter().any(|m_r| m_r.matches(lib_r)) {
+        return Some(Prog::PresentInMap(lib_r.clone()));
+    }
+    // if lib_r is a retagged or moved version of a track in map, that isn't still in lib
+    map.iter()
+        .find(|m_r| m_r.same_track(lib_r) && !lib.iter().any(|other_r| m_r.matches(other_r)))
+        .map(|m_r| {
+            let map_r = MapRec {
+                searched_at: m_r.searched_at,
+                ..lib_r.to_map_record(&m_r.sp_id)
+            };
+            Prog::Retagged(map_r, m_r.name.to_owned())
+        })
+}
+
+pub async fn map(
+    lib_path: PathBuf,
+    map_path: PathBuf,
+    mut opts: MapOpts,
+    config: &Config,
+) -> anyhow::Result<()> {
+    opts.match_opts.apply_config(config);
+    let ladder = Arc::new(parse_ladder(&opts.search_ladder, config)?);
+    let search_sps = get_search_sps(config, opts.jobs.max(1)).await?;
+    let cache = Arc::new(Mutex::new(SearchCache::open(
+        opts.cache_ttl
+            .or(config.cache_ttl)
+            .unwrap_or(DEFAULT_TTL_DAYS),
+        opts.refresh,
+    )?));
 
     let lib: Vec<LibRec> = collect_csv(&lib_path, true)?;
     let map: Vec<MapRec> = if map_path.exists() {
@@ -201,84 +917,116 @@ pub async fn map(lib_path: PathBuf, map_path: PathBuf) -> anyhow::Result<()> {
         Vec::new()
     };
 
-    // TODO add argument to manually specify bak file
-    let prog_path = {
-        let mut file_name = lib_path.file_name().unwrap().to_owned();
-        file_name.push("_progress.bak");
-        PathBuf::from(file_name)
-    };
+    let prog_path = opts
+        .progress_path
+        .clone()
+        .or_else(|| config.progress_path.clone())
+        .unwrap_or_else(|| {
+            let mut file_name = lib_path.file_name().unwrap().to_owned();
+            file_name.push("_progress.bak");
+            PathBuf::from(file_name)
+        });
 
-    let mut prog_map = ProgMap::new(&prog_path, &lib_path)?;
+    let mut prog_map = ProgMap::new(&prog_path, &lib_path, !opts.non_interactive)?;
+    let mut review_queue = if opts.non_interactive {
+        let review_path = opts
+            .review_path
+            .clone()
+            .unwrap_or_else(|| default_review_path(&map_path));
+        Some(ReviewQueue::open(&review_path)?)
+    } else {
+        None
+    };
+    let to_search = lib
+        .iter()
+        .enumerate()
+        .skip(prog_map.index())
+        .filter(|(_, lib_r)| precheck(lib_r, &lib, &map).is_none())
+        .map(|(index, lib_r)| (index, lib_r.clone()))
+        .collect();
+    let searcher_sp = search_sps[0].clone();
+    let mut searches = search_ahead(
+        to_search,
+        search_sps,
+        cache.clone(),
+        config.market.clone(),
+        ladder.clone(),
+    );
+    let mut searcher = Searcher::new(config, searcher_sp, cache, ladder);
+    let mut ui = Ui::new(opts.tui);
+    // kept so that undoing can go back over tracks that were already searched for
+    let mut found: HashMap<usize, Vec<SpTrack>> = HashMap::new();
+    // lines decided at the prompt this run, most recent last
+    let mut prompted: Vec<usize> = Vec::new();
     while prog_map.index() < lib.len() {
-        let lib_r = lib[prog_map.index()].clone();
-        if lib_r.name.trim().is_empty() {
-            prog_map.push_rec(Prog::MissingName)?;
-            continue;
-        }
-        // if lib_r already present in map
-        if map.iter().any(|m_r| m_r.matches(&lib_r)) {
-            prog_map.push_rec(Prog::PresentInMap(lib_r))?;
+        let index = prog_map.index();
+        let lib_r = lib[index].clone();
+        if let Some(prog) = precheck(&lib_r, &lib, &map) {
+            prog_map.push_rec(prog)?;
             continue;
         }
         // else add lib_r to map
-        // TODO let user choose market
-        let search_results = search_tracks(&lib_r, &mut cred_sp).await?;
-        if search_results.is_empty() {
-            prog_map.push_rec(Prog::NotFoundSearch(lib_r))?;
-            continue;
-        }
-        if let Some(track) = search_results.iter().find(|tr| lib_r.matches_track(tr)) {
-            prog_map.push_rec(Prog::AutomaticallyChosenSearch(
-                lib_r.to_map_record(&track.id),
-            ))?;
+        let search_results = loop {
+            if let Some(search_results) = found.get(&index) {
+                break search_results.clone();
+            }
+            let (found_index, search_results) = searches
+                .recv()
+                .await
+                .ok_or(anyhow!("Searching stopped unexpectedly"))??;
+            found.insert(found_index, search_results);
+        };
+        let (scores, search_results) = match auto_choose(&lib_r, search_results, &opts.match_opts) {
+            AutoChoice::NotFound => {
+                prog_map.push_rec(Prog::NotFoundSearch(lib_r))?;
+                continue;
+            }
+            AutoChoice::Isrc(id) => {
+                prog_map.push_rec(Prog::IsrcMatch(lib_r.to_map_record(&id)))?;
+                continue;
+            }
+            AutoChoice::Confident(id) => {
+                prog_map.push_rec(Prog::AutomaticallyChosenSearch(lib_r.to_map_record(&id)))?;
+                continue;
+            }
+            AutoChoice::Unsure(scores, search_results) => (scores, search_results),
+        };
+        if let Some(review_queue) = review_queue.as_mut() {
+            review_queue.push(&lib_r, &search_results)?;
+            prog_map.push_rec(Prog::Queued(lib_r))?;
             continue;
         }
-        println!("=== Track to match ==============================");
-        println!("{lib_r}\n");
-        println!("=== Search results ====================");
-        for (i, item) in search_results.iter().enumerate() {
-            println!("= Search result {} =", i + 1);
-            print_track(item);
-            println!();
-        }
-        let tracks_len = search_results.len();
-        enum Ans {
-            NotFound,
-            Ind(usize),
-            Manual(String),
-        }
-        let answer = (|| -> anyhow::Result<Ans> {
-            let mut answer = String::new();
-            loop {
-                print!("Pick a track to match (#/s/n): ");
-                io::stdout().flush()?;
-                io::stdin().read_line(&mut answer)?;
-                answer = answer.trim().to_lowercase();
-                if answer == "n" {
-                    return Ok(Ans::NotFound);
-                }
-                if answer == "s" {
-                    print!("Please manually enter the spotify id: ");
-                    answer = String::new();
-                    io::stdout().flush()?;
-                    io::stdin().read_line(&mut answer)?;
-                    return Ok(Ans::Manual(answer.trim().to_owned()));
-                }
-                if let Ok(i) = answer.parse::<usize>() {
-                    if i > 0 && i < tracks_len + 1 {
-                        return Ok(Ans::Ind(i));
-                    }
-                }
-                answer = String::new();
-            }
-        })()?;
+        let answer = ui
+            .choose_track(
+                &lib_r,
+                search_results,
+                scores,
+                &opts.match_opts,
+                &mut searcher,
+                !prompted.is_empty(),
+            )
+            .await?;
         match answer {
+            Ans::Chosen(id) => prog_map.push_rec(Prog::ChosenSearch(lib_r.to_map_record(&id)))?,
             Ans::NotFound => prog_map.push_rec(Prog::RejectedSearch(lib_r))?,
-            Ans::Ind(index) => prog_map.push_rec(Prog::ChosenSearch(
-                lib_r.to_map_record(&search_results[index - 1].id),
-            ))?,
-            Ans::Manual(id) => prog_map.push_rec(Prog::ChosenSearch(lib_r.to_map_record(&id)))?,
+            Ans::Skip => prog_map.push_rec(Prog::Skipped(lib_r))?,
+            Ans::Undo => {
+                let undone = prompted.pop().unwrap();
+                prog_map.undo(undone)?;
+                info!("line {}, \"{}\" undone", undone + 1, lib[undone].name);
+                continue;
+            }
+            Ans::Quit => {
+                ui.close();
+                info!(
+                    "Stopped at line {}, progress is kept in {} for the next run",
+                    index + 1,
+                    prog_path.to_string_lossy()
+                );
+                return Ok(());
+            }
         }
+        prompted.push(index);
     }
 
     // my fweaking GIWLFWIEND made me write this comment :P
@@ -287,14 +1035,25 @@ pub async fn map(lib_path: PathBuf, map_path: PathBuf) -> anyhow::Result<()> {
     let mut map: Vec<MapRec> = map
         .into_iter()
         .enumerate()
-        .filter_map(|(map_i, map_rec)| {
-            if lib.iter().any(|lib_rec| map_rec.matches(&lib_rec)) {
+        .filter_map(|(map_i, mut map_rec)| {
+            if let Some(lib_rec) = lib.iter().find(|lib_rec| map_rec.matches(lib_rec)) {
+                map_rec.path = lib_rec.path.to_owned();
+                map_rec.track_key = lib_rec.track_key.to_owned();
+                map_rec.mbid = lib_rec.mbid.to_owned();
                 Some(map_rec)
+            } else if lib.iter().any(|lib_rec| map_rec.same_track(lib_rec)) {
+                info!(
+                    "map line {}, \"{}\" replaced by its retagged entry",
+                    map_i + 1,
+                    map_rec.name,
+                );
+                None
             } else {
                 warn!(
-                    "map line {}, \"{}\" removed as not present in lib",
+                    "map line {}, \"{}\" ({}) removed as not present in lib",
                     map_i + 1,
-                    map_rec.name
+                    map_rec.name,
+                    map_rec.path,
                 );
                 None
             }
@@ -307,27 +1066,137 @@ pub async fn map(lib_path: PathBuf, map_path: PathBuf) -> anyhow::Result<()> {
         .filter(|rec| !rec.name.is_empty())
         .for_each(|rec| map.push(rec));
 
-    map.sort_by_key(|m_r| m_r.name.clone());
-    map.sort_by_key(|m_r| m_r.album.clone());
-    map.sort_by_key(|m_r| m_r.artist.clone());
+    write_map(&map_path, &mut map)?;
+    fs::remove_file(prog_path)?;
+    Ok(())
+}
 
-    let temp_map_path = {
-        let mut file_name = map_path.file_name().unwrap().to_owned();
-        file_name.push(".tmp");
-        PathBuf::from(file_name)
-    };
-    if temp_map_path.exists() {
-        return Err(anyhow!(format!(
-            "Path {} already exists",
-            temp_map_path.to_string_lossy()
-        )));
+/// Searches again for the tracks in the map that weren't found, and fills in any that are found
+/// now. The rest of the map is left as it is
+pub async fn remap(map_path: PathBuf, mut opts: RemapOpts, config: &Config) -> anyhow::Result<()> {
+    opts.match_opts.apply_config(config);
+    let ladder = Arc::new(parse_ladder(&opts.search_ladder, config)?);
+    let mut map: Vec<MapRec> = collect_csv(&map_path, true)?;
+    // in the order it will be written in, so that the indices below stay put
+    sort_map(&mut map);
+    let retry: Vec<usize> = map
+        .iter()
+        .enumerate()
+        .filter(|(_, m_r)| m_r.sp_id == "Not found" && opts.selects(m_r))
+        .map(|(i, _)| i)
+        .collect();
+    if retry.is_empty() {
+        info!("No \"Not found\" tracks to search for");
+        return Ok(());
     }
-    let mut wtr = csv::Writer::from_path(&temp_map_path)?;
-    for map_r in map.into_iter() {
-        wtr.serialize(map_r)?;
+    info!("Searching again for {} \"Not found\" tracks", retry.len());
+
+    let search_sps = get_search_sps(config, opts.jobs.max(1)).await?;
+    // results are always fresh, as the point is to find what spotify has added since
+    let cache = Arc::new(Mutex::new(SearchCache::open(
+        config.cache_ttl.unwrap_or(DEFAULT_TTL_DAYS),
+        true,
+    )?));
+    let to_search = retry.iter().map(|&i| (i, map[i].to_lib_record())).collect();
+    let searcher_sp = search_sps[0].clone();
+    let mut searches = search_ahead(
+        to_search,
+        search_sps,
+        cache.clone(),
+        config.market.clone(),
+        ladder.clone(),
+    );
+    let mut searcher = Searcher::new(config, searcher_sp, cache, ladder);
+    let mut ui = Ui::new(opts.tui);
+    let mut found: HashMap<usize, Vec<SpTrack>> = HashMap::new();
+    // positions in `retry` decided at the prompt, with the record as it was before
+    let mut prompted: Vec<(usize, MapRec)> = Vec::new();
+    let mut pos = 0;
+    while pos < retry.len() {
+        let i = retry[pos];
+        let lib_r = map[i].to_lib_record();
+        let search_results = loop {
+            if let Some(search_results) = found.get(&i) {
+                break search_results.clone();
+            }
+            let (found_index, search_results) = searches
+                .recv()
+                .await
+                .ok_or(anyhow!("Searching stopped unexpectedly"))??;
+            found.insert(found_index, search_results);
+        };
+        let searched_at = Some(unix_time());
+        match auto_choose(&lib_r, search_results, &opts.match_opts) {
+            AutoChoice::NotFound => {
+                info!("\"{}\" still not found", lib_r.name);
+                map[i].searched_at = searched_at;
+            }
+            AutoChoice::Isrc(id) | AutoChoice::Confident(id) => {
+                info!("\"{}\" found, added with id: {}", lib_r.name, id);
+                map[i].sp_id = id;
+                map[i].searched_at = searched_at;
+            }
+            AutoChoice::Unsure(..) if opts.non_interactive => {
+                info!(
+                    "\"{}\" has no confident match, left as \"Not found\"",
+                    lib_r.name
+                );
+            }
+            AutoChoice::Unsure(scores, search_results) => {
+                let answer = ui
+                    .choose_track(
+                        &lib_r,
+                        search_results,
+                        scores,
+                        &opts.match_opts,
+                        &mut searcher,
+                        !prompted.is_empty(),
+                    )
+                    .await?;
+                let before = map[i].clone();
+                match answer {
+                    Ans::Chosen(id) => {
+                        info!("\"{}\" added with id: {}", lib_r.name, id);
+                        map[i].sp_id = id;
+                        map[i].searched_at = searched_at;
+                    }
+                    Ans::NotFound => map[i].searched_at = searched_at,
+                    Ans::Skip => info!("\"{}\" skipped", lib_r.name),
+                    Ans::Undo => {
+                        let (undone, before) = prompted.pop().unwrap();
+                        info!("\"{}\" undone", before.name);
+                        map[retry[undone]] = before;
+                        write_map(&map_path, &mut map)?;
+                        pos = undone;
+                        continue;
+                    }
+                    Ans::Quit => {
+                        ui.close();
+                        write_map(&map_path, &mut map)?;
+                        info!(
+                            "Stopped with {} tracks left to search for again",
+                            retry.len() - pos
+                        );
+                        return Ok(());
+                    }
+                }
+                prompted.push((pos, before));
+                write_map(&map_path, &mut map)?;
+            }
+        }
+        pos += 1;
     }
-    wtr.flush()?;
-    fs::rename(temp_map_path, map_path)?;
-    fs::remove_file(prog_path)?;
+    ui.close();
+
+    write_map(&map_path, &mut map)?;
+    let still_not_found = retry
+        .iter()
+        .filter(|&&i| map[i].sp_id == "Not found")
+        .count();
+    info!(
+        "Found {} of {} tracks",
+        retry.len() - still_not_found,
+        retry.len()
+    );
     Ok(())
 }
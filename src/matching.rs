@@ -0,0 +1,244 @@
+use strsim::normalized_levenshtein;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::{spotify::SpTrack, LibRec};
+
+/// Words that mark a different release of the same recording, e.g. "(Remastered 2011)" or
+/// "- Radio Edit". Bracketed or dashed suffixes containing one of these are ignored.
+const VERSION_WORDS: [&str; 14] = [
+    "remaster",
+    "remastered",
+    "live",
+    "edit",
+    "version",
+    "mono",
+    "stereo",
+    "mix",
+    "deluxe",
+    "bonus",
+    "explicit",
+    "feat",
+    "ft",
+    "single",
+];
+
+const NAME_WEIGHT: f64 = 0.5;
+const ARTIST_WEIGHT: f64 = 0.3;
+const ALBUM_WEIGHT: f64 = 0.2;
+/// Most that a duration outside the tolerance can take off the score
+const DURATION_PENALTY: f64 = 0.2;
+
+fn is_version_suffix(s: &str) -> bool {
+    fold(s)
+        .split_whitespace()
+        .any(|word| VERSION_WORDS.contains(&word))
+}
+
+/// Removes "(...)", "[...]" and " - ..." suffixes that describe the version of the track
+pub fn strip_version_suffixes(s: &str) -> String {
+    let mut out = s.trim().to_owned();
+    loop {
+        let before = out.len();
+        if let Some(close) = out.chars().last().filter(|c| *c == ')' || *c == ']') {
+            let open = if close == ')' { '(' } else { '[' };
+            if let Some(start) = out.rfind(open) {
+                if is_version_suffix(&out[start..]) {
+                    out.truncate(start);
+                }
+            }
+        }
+        if let Some(start) = out.rfind(" - ") {
+            if is_version_suffix(&out[start..]) {
+                out.truncate(start);
+            }
+        }
+        out = out.trim().to_owned();
+        if out.len() == before {
+            return out;
+        }
+    }
+}
+
+/// Lowercases, removes accents and replaces punctuation with spaces
+fn fold(s: &str) -> String {
+    let folded: String = s
+        .replace('&', " and ")
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    folded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+pub fn normalise(s: &str) -> String {
+    let stripped = strip_version_suffixes(s);
+    // don't strip a title down to nothing, e.g. a song called "Live"
+    if stripped.is_empty() {
+        fold(s)
+    } else {
+        fold(&stripped)
+    }
+}
+
+fn similarity(a: &str, b: &str) -> f64 {
+    normalized_levenshtein(&normalise(a), &normalise(b))
+}
+
+/// How confident we are that `tr` is `lib_r`, from 0 to 1
+pub fn score(lib_r: &LibRec, tr: &SpTrack, duration_tolerance_ms: u32) -> f64 {
+    let name = similarity(&lib_r.name, &tr.name);
+    let album = similarity(&lib_r.album, &tr.album);
+    let artist = lib_r
+        .artist_list()
+        .iter()
+        .flat_map(|a| tr.artists.iter().map(move |at| similarity(a, at)))
+        .fold(0.0, f64::max);
+    let mut score = NAME_WEIGHT * name + ARTIST_WEIGHT * artist + ALBUM_WEIGHT * album;
+    if let Some(duration_ms) = lib_r.duration_ms {
+        let delta = duration_ms.abs_diff(tr.duration_ms);
+        if delta > duration_tolerance_ms {
+            let over = (delta - duration_tolerance_ms) as f64 / duration_tolerance_ms.max(1) as f64;
+            score -= DURATION_PENALTY * over.min(1.0);
+        }
+    }
+    score.max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lib_rec(name: &str, album: &str, artist: &str, duration_ms: Option<u32>) -> LibRec {
+        LibRec {
+            name: name.to_owned(),
+            album: album.to_owned(),
+            artist: artist.to_owned(),
+            duration_ms,
+            ..Default::default()
+        }
+    }
+
+    fn sp_track(name: &str, album: &str, artists: &[&str], duration_ms: u32) -> SpTrack {
+        SpTrack {
+            id: String::new(),
+            name: name.to_owned(),
+            album: album.to_owned(),
+            artists: artists.iter().map(|a| a.to_string()).collect(),
+            release_date: String::new(),
+            duration_ms,
+            isrc: None,
+            explicit: false,
+            popularity: None,
+        }
+    }
+
+    const TOLERANCE_MS: u32 = 3000;
+
+    #[test]
+    fn version_suffixes_are_stripped() {
+        assert_eq!(
+            strip_version_suffixes("Hey Jude - Remastered 2015"),
+            "Hey Jude"
+        );
+        assert_eq!(
+            strip_version_suffixes("Ghosts (Live) [2004 Remaster]"),
+            "Ghosts"
+        );
+        assert_eq!(strip_version_suffixes("Creep - Radio Edit"), "Creep");
+        assert_eq!(
+            strip_version_suffixes("Without Me (feat. Juice WRLD)"),
+            "Without Me"
+        );
+    }
+
+    #[test]
+    fn other_suffixes_are_kept() {
+        assert_eq!(
+            strip_version_suffixes("Song (From the Film)"),
+            "Song (From the Film)"
+        );
+        assert_eq!(
+            strip_version_suffixes("Part I - Part II"),
+            "Part I - Part II"
+        );
+    }
+
+    #[test]
+    fn remaster_suffixes_in_either_style_match() {
+        assert_eq!(normalise("Song (Remastered 2011)"), "song");
+        assert_eq!(
+            normalise("Song (Remastered 2011)"),
+            normalise("Song - 2011 Remaster")
+        );
+    }
+
+    #[test]
+    fn titles_are_not_stripped_to_nothing() {
+        assert_eq!(normalise("Live"), "live");
+        assert_eq!(normalise("(Live)"), "live");
+        assert_eq!(normalise("(Live) [Remastered]"), "live remastered");
+    }
+
+    #[test]
+    fn accents_case_and_punctuation_are_folded() {
+        assert_eq!(normalise("Beyoncé"), normalise("BEYONCE"));
+        assert_eq!(normalise("Sigur Rós"), "sigur ros");
+        assert_eq!(normalise("Hello, World!"), "hello world");
+        assert_eq!(normalise("Simon & Garfunkel"), "simon and garfunkel");
+        assert_eq!(normalise("ｆｕｌｌｗｉｄｔｈ"), "fullwidth");
+    }
+
+    #[test]
+    fn remastered_release_scores_above_threshold() {
+        let lib_r = lib_rec("Song (Remastered 2011)", "Album", "Artist", Some(200_000));
+        let tr = sp_track("Song - 2011 Remaster", "Album", &["Artist"], 201_000);
+        assert!(score(&lib_r, &tr, TOLERANCE_MS) >= 0.9);
+    }
+
+    #[test]
+    fn exact_match_scores_one() {
+        let lib_r = lib_rec("Song", "Album", "Artist", Some(200_000));
+        let tr = sp_track("Song", "Album", &["Artist"], 200_000);
+        assert_eq!(score(&lib_r, &tr, TOLERANCE_MS), 1.0);
+    }
+
+    #[test]
+    fn different_song_scores_below_threshold() {
+        let lib_r = lib_rec("Yesterday", "Help!", "The Beatles", None);
+        let tr = sp_track("Let It Be", "Let It Be", &["The Beatles"], 243_000);
+        assert!(score(&lib_r, &tr, TOLERANCE_MS) < 0.9);
+    }
+
+    #[test]
+    fn best_matching_artist_counts() {
+        let mut lib_r = lib_rec("Get Lucky", "Random Access Memories", "", None);
+        lib_r.artists = vec!["Daft Punk".to_owned(), "Pharrell Williams".to_owned()];
+        let tr = sp_track(
+            "Get Lucky",
+            "Random Access Memories",
+            &["Pharrell Williams", "Daft Punk", "Nile Rodgers"],
+            369_000,
+        );
+        assert_eq!(score(&lib_r, &tr, TOLERANCE_MS), 1.0);
+    }
+
+    #[test]
+    fn duration_within_tolerance_isnt_penalised() {
+        let lib_r = lib_rec("Song", "Album", "Artist", Some(200_000));
+        let tr = sp_track("Song", "Album", &["Artist"], 203_000);
+        assert_eq!(score(&lib_r, &tr, TOLERANCE_MS), 1.0);
+    }
+
+    #[test]
+    fn duration_penalty_grows_then_caps() {
+        let lib_r = lib_rec("Song", "Album", "Artist", Some(200_000));
+        // half the tolerance over it takes off half the penalty
+        let tr = sp_track("Song", "Album", &["Artist"], 204_500);
+        let half = score(&lib_r, &tr, TOLERANCE_MS);
+        assert!((half - (1.0 - DURATION_PENALTY / 2.0)).abs() < 1e-9);
+        let tr = sp_track("Song", "Album", &["Artist"], 400_000);
+        let capped = score(&lib_r, &tr, TOLERANCE_MS);
+        assert!((capped - (1.0 - DURATION_PENALTY)).abs() < 1e-9);
+    }
+}
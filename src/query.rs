@@ -0,0 +1,352 @@
+use anyhow::anyhow;
+
+use crate::{config::Config, matching::strip_version_suffixes, LibRec};
+
+/// Tried in order by `map` until enough search results are found
+pub const DEFAULT_SEARCH_LADDER: [&str; 5] = [
+    "track:{name} album:{album} artist:{artist}",
+    "{name} album:{album}",
+    "{name} {artist}",
+    "track:{name:strip} artist:{artist}",
+    "{name:strip}",
+];
+
+/// The ladder given on the command line, otherwise the configured one, otherwise the default
+pub fn parse_ladder(templates: &[String], config: &Config) -> anyhow::Result<Vec<QueryTemplate>> {
+    let templates = if !templates.is_empty() {
+        templates.to_vec()
+    } else if let Some(ladder) = &config.search_ladder {
+        ladder.clone()
+    } else {
+        DEFAULT_SEARCH_LADDER.map(String::from).to_vec()
+    };
+    templates
+        .iter()
+        .map(|template| QueryTemplate::parse(template))
+        .collect()
+}
+
+/// Spotify reads these as operators when they're in capitals
+const OPERATORS: [&str; 3] = ["AND", "OR", "NOT"];
+
+#[derive(Debug, PartialEq)]
+enum QueryPart {
+    Lit(String),
+    Field { name: String, strip: bool },
+}
+
+/// One whitespace separated term of a query, e.g. `artist:{artist}`
+#[derive(Debug)]
+struct Term {
+    /// the spotify field filter the term starts with, if any
+    filter: Option<String>,
+    parts: Vec<QueryPart>,
+    quote: bool,
+}
+
+/// A search query with fields filled in from the library, e.g. "track:{name} artist:{artist}".
+/// Terms with an empty field are left out, so that one template works for tracks with and without
+/// an album
+#[derive(Debug)]
+pub struct QueryTemplate {
+    terms: Vec<Term>,
+}
+
+impl QueryTemplate {
+    const FIELDS: [&'static str; 5] = ["name", "album", "artist", "album_artist", "year"];
+    /// `strip` removes bracketed and version suffixes, e.g. " - Remastered 2011", `quote` searches
+    /// for the term as an exact phrase
+    const MODIFIERS: [&'static str; 2] = ["strip", "quote"];
+
+    pub fn parse(template: &str) -> anyhow::Result<Self> {
+        let mut terms = Vec::new();
+        for term in template.split_whitespace() {
+            let (filter, mut rest) = match term.split_once(':') {
+                Some((filter, rest))
+                    if !filter.is_empty()
+                        && !filter.contains('{')
+                        && filter.chars().all(|c| c.is_ascii_alphabetic()) =>
+                {
+                    (Some(filter.to_owned()), rest)
+                }
+                _ => (None, term),
+            };
+            let mut parts = Vec::new();
+            let mut quote = false;
+            while let Some(start) = rest.find('{') {
+                if start > 0 {
+                    parts.push(QueryPart::Lit(rest[..start].to_owned()));
+                }
+                let end = rest[start..]
+                    .find('}')
+                    .ok_or(anyhow!("unclosed {{ in search template \"{}\"", template))?;
+                let mut field = rest[start + 1..start + end].split(':');
+                let name = field.next().unwrap_or_default();
+                if !Self::FIELDS.contains(&name) {
+                    return Err(anyhow!(
+                        "unknown field {{{}}} in search template \"{}\", expected one of {:?}",
+                        name,
+                        template,
+                        Self::FIELDS
+                    ));
+                }
+                let mut strip = false;
+                for modifier in field.flat_map(|mods| mods.split(',')) {
+                    match modifier {
+                        "strip" => strip = true,
+                        "quote" => quote = true,
+                        _ => {
+                            return Err(anyhow!(
+                                "unknown modifier \"{}\" in search template \"{}\", expected one of {:?}",
+                                modifier,
+                                template,
+                                Self::MODIFIERS
+                            ))
+                        }
+                    }
+                }
+                parts.push(QueryPart::Field {
+                    name: name.to_owned(),
+                    strip,
+                });
+                rest = &rest[start + end + 1..];
+            }
+            if !rest.is_empty() {
+                parts.push(QueryPart::Lit(rest.to_owned()));
+            }
+            terms.push(Term {
+                filter,
+                parts,
+                quote,
+            });
+        }
+        Ok(Self { terms })
+    }
+
+    /// The query for `lib_r`, empty if none of its terms could be filled in
+    pub fn render(&self, lib_r: &LibRec) -> String {
+        let mut out = Vec::new();
+        'terms: for term in &self.terms {
+            let mut val = String::new();
+            for part in &term.parts {
+                match part {
+                    QueryPart::Lit(lit) => val += lit,
+                    QueryPart::Field { name, strip } => {
+                        let field = field_val(lib_r, name);
+                        let field = if *strip {
+                            strip_version_suffixes(&strip_brackets(&field))
+                        } else {
+                            field
+                        };
+                        if field.trim().is_empty() {
+                            continue 'terms;
+                        }
+                        val += field.trim();
+                    }
+                }
+            }
+            // field filters are always quoted, otherwise only the first word would be filtered on
+            let val = if term.quote || term.filter.is_some() {
+                quote(&val)
+            } else {
+                escape_free_text(&val)
+            };
+            if val.is_empty() {
+                continue;
+            }
+            match &term.filter {
+                Some(filter) => out.push(format!("{}:{}", filter, val)),
+                None => out.push(val),
+            }
+        }
+        out.join(" ")
+    }
+}
+
+/// Quotes `val` as a phrase. Quotes can't be escaped in spotify's search so they're dropped, anything
+/// else is taken literally between them. Empty if there is nothing left to quote
+fn quote(val: &str) -> String {
+    let words: Vec<&str> = val
+        .split(|c: char| c == '"' || c.is_whitespace())
+        .filter(|w| !w.is_empty())
+        .collect();
+    if words.is_empty() {
+        String::new()
+    } else {
+        format!("\"{}\"", words.join(" "))
+    }
+}
+
+/// Takes out anything spotify would read as search syntax: quotes, colons that would make a word a
+/// field filter, leading hyphens that would exclude a word, and operators
+fn escape_free_text(val: &str) -> String {
+    val.replace(['"', ':'], " ")
+        .split_whitespace()
+        .map(|word| word.trim_start_matches('-'))
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            if OPERATORS.contains(&word) {
+                word.to_lowercase()
+            } else {
+                word.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn field_val(lib_r: &LibRec, name: &str) -> String {
+    match name {
+        "name" => lib_r.name.to_owned(),
+        "album" => lib_r.album.to_owned(),
+        "artist" => lib_r.artist_list()[0].to_owned(),
+        "album_artist" => lib_r.album_artist.to_owned(),
+        "year" => lib_r.year.map(|y| y.to_string()).unwrap_or_default(),
+        _ => unreachable!(),
+    }
+}
+
+/// Removes everything in round or square brackets, e.g. "Song (From the Film) [Live]" to "Song"
+fn strip_brackets(s: &str) -> String {
+    let mut out = String::new();
+    let mut depth = 0;
+    for c in s.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' if depth > 0 => depth -= 1,
+            _ if depth == 0 => out.push(c),
+            _ => {}
+        }
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(name: &str, album: &str, artist: &str) -> LibRec {
+        LibRec {
+            name: name.to_owned(),
+            album: album.to_owned(),
+            artist: artist.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn render(template: &str, lib_r: &LibRec) -> String {
+        QueryTemplate::parse(template).unwrap().render(lib_r)
+    }
+
+    const FULL: &str = "track:{name} album:{album} artist:{artist}";
+
+    #[test]
+    fn filters_are_quoted() {
+        assert_eq!(
+            render(FULL, &rec("Don't Stop Me Now", "Jazz", "Queen")),
+            r#"track:"Don't Stop Me Now" album:"Jazz" artist:"Queen""#
+        );
+    }
+
+    #[test]
+    fn quotes_in_titles_are_dropped() {
+        assert_eq!(
+            render(FULL, &rec("\"Heroes\"", "\"Heroes\"", "David Bowie")),
+            r#"track:"Heroes" album:"Heroes" artist:"David Bowie""#
+        );
+        assert_eq!(render("{name}", &rec("Say \"Hello\"", "", "")), "Say Hello");
+    }
+
+    #[test]
+    fn colons_only_matter_outside_quotes() {
+        let lib_r = rec("Re: Stacks", "For Emma, Forever Ago", "Bon Iver");
+        assert_eq!(
+            render(FULL, &lib_r),
+            r#"track:"Re: Stacks" album:"For Emma, Forever Ago" artist:"Bon Iver""#
+        );
+        assert_eq!(render("{name} {artist}", &lib_r), "Re Stacks Bon Iver");
+        assert_eq!(
+            render(
+                "album:{album}",
+                &rec("", "Star Wars: Episode IV - A New Hope", "")
+            ),
+            r#"album:"Star Wars: Episode IV - A New Hope""#
+        );
+    }
+
+    #[test]
+    fn leading_hyphens_dont_exclude_words() {
+        let lib_r = rec("Eyes Closed", "-", "Ed Sheeran");
+        assert_eq!(
+            render(FULL, &lib_r),
+            r#"track:"Eyes Closed" album:"-" artist:"Ed Sheeran""#
+        );
+        // a free text "-" would exclude nothing, so the term is left out
+        assert_eq!(render("{name} {album}", &lib_r), "Eyes Closed");
+        assert_eq!(
+            render("{name}", &rec("Self-Control", "", "")),
+            "Self-Control"
+        );
+    }
+
+    #[test]
+    fn operators_are_lowercased_in_free_text() {
+        let lib_r = rec("NOT TODAY", "YOU NEVER WALK ALONE", "BTS");
+        assert_eq!(render("{name} {artist}", &lib_r), "not TODAY BTS");
+        assert_eq!(render("track:{name}", &lib_r), r#"track:"NOT TODAY""#);
+    }
+
+    #[test]
+    fn non_latin_scripts_are_kept() {
+        assert_eq!(
+            render(FULL, &rec("紅蓮華", "紅蓮華", "LiSA")),
+            r#"track:"紅蓮華" album:"紅蓮華" artist:"LiSA""#
+        );
+        assert_eq!(
+            render(
+                "{name} {artist}",
+                &rec("Пачка сигарет", "Звезда по имени Солнце", "Кино")
+            ),
+            "Пачка сигарет Кино"
+        );
+    }
+
+    #[test]
+    fn empty_fields_drop_their_term() {
+        assert_eq!(
+            render(FULL, &rec("Windowlicker", "", "Aphex Twin")),
+            r#"track:"Windowlicker" artist:"Aphex Twin""#
+        );
+        assert_eq!(render("album:{album}", &rec("Windowlicker", "", "")), "");
+    }
+
+    #[test]
+    fn strip_and_quote_modifiers() {
+        let lib_r = rec("Hey Jude - Remastered 2015", "", "The Beatles");
+        assert_eq!(
+            render("track:{name:strip} artist:{artist}", &lib_r),
+            r#"track:"Hey Jude" artist:"The Beatles""#
+        );
+        assert_eq!(
+            render(
+                "{name:strip,quote}",
+                &rec("Ghosts (Live) [2004 Remaster]", "", "")
+            ),
+            r#""Ghosts""#
+        );
+    }
+
+    #[test]
+    fn first_artist_is_searched() {
+        let mut lib_r = rec("Get Lucky", "", "Daft Punk; Pharrell Williams");
+        lib_r.artists = vec!["Daft Punk".to_owned(), "Pharrell Williams".to_owned()];
+        assert_eq!(render("artist:{artist}", &lib_r), r#"artist:"Daft Punk""#);
+    }
+
+    #[test]
+    fn bad_templates_are_rejected() {
+        assert!(QueryTemplate::parse("track:{title}").is_err());
+        assert!(QueryTemplate::parse("track:{name:loud}").is_err());
+        assert!(QueryTemplate::parse("track:{name").is_err());
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::time::{sleep_until, Instant};
+
+/// A call is given up on after failing this many times
+const MAX_ATTEMPTS: u32 = 8;
+/// First wait after being rate limited, doubled on every attempt
+const RATE_LIMIT_DELAY: Duration = Duration::from_secs(1);
+/// First wait after a server error or a dropped connection, doubled on every attempt
+const TRANSIENT_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Time spent waiting to retry, across every call made by this run
+static WAITED_MS: AtomicU64 = AtomicU64::new(0);
+/// Set when any call is rate limited, so that concurrent calls hold off too instead of making it
+/// worse
+static RATE_LIMITED_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Waits out a rate limit hit by another call
+async fn wait_for_rate_limit() {
+    let until = *RATE_LIMITED_UNTIL.lock().unwrap();
+    if let Some(until) = until {
+        sleep_until(until).await;
+    }
+}
+
+enum Failure {
+    RateLimited,
+    Transient,
+    Fatal,
+}
+
+/// How reqwest describes failing to connect, send the request or read the response, timeouts
+/// included. spotify-rs only passes its errors on as text
+const CONNECTION_FAILURES: [&str; 2] = ["error sending request", "request or response body error"];
+
+fn classify(err: &spotify_rs::Error) -> Failure {
+    match err {
+        spotify_rs::Error::Spotify { status: 429, .. } => Failure::RateLimited,
+        spotify_rs::Error::Spotify { status, .. } if *status >= 500 => Failure::Transient,
+        // not any other Http error, e.g. a response body that couldn't be decoded will be the same
+        // next time
+        spotify_rs::Error::Http(msg)
+            if CONNECTION_FAILURES
+                .iter()
+                .any(|failure| msg.starts_with(failure)) =>
+        {
+            Failure::Transient
+        }
+        _ => Failure::Fatal,
+    }
+}
+
+/// Exponential backoff with jitter, so that concurrent callers don't retry in lockstep
+fn backoff(base: Duration, attempt: u32) -> Duration {
+    let ceiling = base
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(MAX_DELAY);
+    ceiling.mul_f64(0.5 + fastrand::f64() / 2.0)
+}
+
+/// Evaluates `call`, a spotify-rs request future, until it succeeds, fails with an error retrying
+/// won't fix, or has been tried MAX_ATTEMPTS times. `retry` is the `Retry` deciding which.
+///
+/// A macro rather than a function taking a closure, as async closures borrowing the client make
+/// futures the compiler can't prove are Send, which searching ahead on the runtime needs.
+macro_rules! with_retry {
+    ($retry:expr, $call:expr) => {{
+        let mut retry: $crate::retry::Retry = $retry;
+        loop {
+            retry.wait_turn().await;
+            match $call.await {
+                Err(err) if retry.should_retry(&err).await => {}
+                res => break res,
+            }
+        }
+    }};
+}
+pub(crate) use with_retry;
+
+/// When to try a spotify call again, see `with_retry!`.
+///
+/// Spotify sends a Retry-After header with 429s, but spotify-rs drops the response headers before
+/// we see the error, so rate limits are backed off from just like server errors.
+pub struct Retry {
+    /// describes the call in the warnings logged while waiting
+    what: &'static str,
+    retry_transient: bool,
+    attempt: u32,
+}
+
+impl Retry {
+    /// Retries rate limits, server errors and dropped connections
+    pub fn new(what: &'static str) -> Self {
+        Self {
+            what,
+            retry_transient: true,
+            attempt: 1,
+        }
+    }
+
+    /// Only retries rate limits, which spotify rejects without acting on. For calls that can't
+    /// safely be made twice, e.g. adding to a playlist, where a server error or a timeout doesn't
+    /// tell us whether the first call went through
+    pub fn rate_limits_only(what: &'static str) -> Self {
+        Self {
+            retry_transient: false,
+            ..Self::new(what)
+        }
+    }
+
+    /// Waits out a rate limit hit by another call before making this one
+    pub async fn wait_turn(&self) {
+        wait_for_rate_limit().await;
+    }
+
+    /// Whether the call should be made again after failing with `err`, having waited to if so
+    pub async fn should_retry(&mut self, err: &spotify_rs::Error) -> bool {
+        let (base, rate_limited) = match classify(err) {
+            Failure::RateLimited => (RATE_LIMIT_DELAY, true),
+            Failure::Transient if self.retry_transient => (TRANSIENT_DELAY, false),
+            Failure::Transient | Failure::Fatal => return false,
+        };
+        if self.attempt >= MAX_ATTEMPTS {
+            warn!("{} failed {} times, giving up", self.what, self.attempt);
+            return false;
+        }
+        let delay = backoff(base, self.attempt);
+        warn!(
+            "{} failed ({}), retrying in {:.1}s",
+            self.what,
+            err,
+            delay.as_secs_f64()
+        );
+        WAITED_MS.fetch_add(delay.as_millis() as u64, Ordering::Relaxed);
+        let until = Instant::now() + delay;
+        if rate_limited {
+            let mut rate_limited_until = RATE_LIMITED_UNTIL.lock().unwrap();
+            *rate_limited_until = (*rate_limited_until).max(Some(until));
+        }
+        sleep_until(until).await;
+        self.attempt += 1;
+        true
+    }
+}
+
+/// Logs the total time spent waiting on retries, if there was any
+pub fn report_waited() {
+    let waited_ms = WAITED_MS.load(Ordering::Relaxed);
+    if waited_ms > 0 {
+        info!(
+            "Spent {:.1}s waiting to retry spotify requests",
+            waited_ms as f64 / 1000.0
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spotify_err(status: u16) -> spotify_rs::Error {
+        spotify_rs::Error::Spotify {
+            status,
+            message: String::new(),
+        }
+    }
+
+    fn http_err(msg: &str) -> spotify_rs::Error {
+        spotify_rs::Error::Http(msg.to_owned())
+    }
+
+    #[test]
+    fn rate_limits_are_told_apart() {
+        assert!(matches!(classify(&spotify_err(429)), Failure::RateLimited));
+    }
+
+    #[test]
+    fn server_errors_are_transient() {
+        for status in [500, 502, 503] {
+            assert!(matches!(classify(&spotify_err(status)), Failure::Transient));
+        }
+    }
+
+    #[test]
+    fn client_errors_are_fatal() {
+        for status in [400, 401, 403, 404] {
+            assert!(matches!(classify(&spotify_err(status)), Failure::Fatal));
+        }
+        assert!(matches!(
+            classify(&spotify_rs::Error::ExpiredToken),
+            Failure::Fatal
+        ));
+    }
+
+    #[test]
+    fn connection_failures_are_transient() {
+        for msg in [
+            "error sending request for url (https://api.spotify.com/v1/search): operation timed out",
+            "error sending request for url (https://api.spotify.com/v1/search): connection refused",
+            "request or response body error: connection reset by peer",
+        ] {
+            assert!(matches!(classify(&http_err(msg)), Failure::Transient), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn undecodable_responses_are_fatal() {
+        let msg = "error decoding response body: missing field `tracks` at line 1 column 2";
+        assert!(matches!(classify(&http_err(msg)), Failure::Fatal));
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        for attempt in 1..=5 {
+            let ceiling = TRANSIENT_DELAY * 2u32.pow(attempt - 1);
+            for _ in 0..100 {
+                let delay = backoff(TRANSIENT_DELAY, attempt);
+                assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?}", delay);
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        for attempt in [8, 16, 40] {
+            let delay = backoff(RATE_LIMIT_DELAY, attempt);
+            assert!(delay >= MAX_DELAY / 2 && delay <= MAX_DELAY, "{:?}", delay);
+        }
+    }
+}
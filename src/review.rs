@@ -0,0 +1,173 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use clap::Args;
+use log::info;
+
+use crate::{
+    collect_csv,
+    config::Config,
+    map::{default_review_path, write_map, Ans, MatchOpts, Searcher, Ui},
+    query::parse_ladder,
+    search_cache::{SearchCache, DEFAULT_TTL_DAYS},
+    spotify::{get_search_sps, SpTrack},
+    LibRec, MapRec,
+};
+
+/// A track from the library and the ids of its search results, separated by ';'
+type ReviewRec = (LibRec, String);
+
+/// Tracks that `map --non-interactive` couldn't confidently match, waiting for `review`
+pub struct ReviewQueue {
+    recs: Vec<ReviewRec>,
+    writer: csv::Writer<File>,
+}
+
+impl ReviewQueue {
+    pub fn open(review_path: &PathBuf) -> anyhow::Result<Self> {
+        let recs = if review_path.exists() {
+            collect_csv(review_path, false)?
+        } else {
+            Vec::new()
+        };
+        let review_file = File::options()
+            .create(true)
+            .append(true)
+            .open(review_path)?;
+        Ok(Self {
+            recs,
+            writer: csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(review_file),
+        })
+    }
+
+    /// Queues `lib_r` unless it is already waiting for review from a previous run
+    pub fn push(&mut self, lib_r: &LibRec, search_results: &[SpTrack]) -> anyhow::Result<()> {
+        if self.recs.iter().any(|(r, _)| same_tags(r, lib_r)) {
+            return Ok(());
+        }
+        let ids: Vec<&str> = search_results.iter().map(|tr| tr.id.as_str()).collect();
+        let rec = (lib_r.clone(), ids.join(";"));
+        self.writer.serialize(&rec)?;
+        self.writer.flush()?;
+        self.recs.push(rec);
+        Ok(())
+    }
+}
+
+fn same_tags(a: &LibRec, b: &LibRec) -> bool {
+    a.name == b.name && a.album == b.album && a.artist == b.artist && a.path == b.path
+}
+
+fn write_queue(review_path: &Path, queue: &[ReviewRec]) -> anyhow::Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_path(review_path)?;
+    for rec in queue {
+        wtr.serialize(rec)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[derive(Args)]
+pub struct ReviewOpts {
+    #[command(flatten)]
+    match_opts: MatchOpts,
+    /// file written by map --non-interactive, defaults to MAP_FILE_review.csv
+    #[arg(long, value_name = "REVIEW_FILE")]
+    review_path: Option<PathBuf>,
+    /// choose matches in a full screen terminal UI instead of at a prompt
+    #[arg(long)]
+    tui: bool,
+}
+
+pub async fn review(
+    map_path: PathBuf,
+    mut opts: ReviewOpts,
+    config: &Config,
+) -> anyhow::Result<()> {
+    opts.match_opts.apply_config(config);
+    let review_path = opts
+        .review_path
+        .clone()
+        .unwrap_or_else(|| default_review_path(&map_path));
+    let mut queue: Vec<ReviewRec> = collect_csv(&review_path, false).with_context(|| {
+        format!(
+            "could not read review file {}",
+            review_path.to_string_lossy()
+        )
+    })?;
+    let mut map: Vec<MapRec> = if map_path.exists() {
+        collect_csv(&map_path, true)?
+    } else {
+        Vec::new()
+    };
+    // only searches one at a time, so one client
+    let search_sp = get_search_sps(config, 1).await?.remove(0);
+    let cache = Arc::new(Mutex::new(SearchCache::open(
+        config.cache_ttl.unwrap_or(DEFAULT_TTL_DAYS),
+        false,
+    )?));
+    let ladder = Arc::new(parse_ladder(&[], config)?);
+    let mut searcher = Searcher::new(config, search_sp, cache, ladder);
+    let mut ui = Ui::new(opts.tui);
+
+    // tracks skipped for now stay in the queue, so this is the next one not skipped
+    let mut i = 0;
+    while let Some((lib_r, ids)) = queue.get(i).cloned() {
+        info!("{} tracks left to review", queue.len() - i);
+        if map.iter().any(|m_r| m_r.matches(&lib_r)) {
+            info!("\"{}\" already present in map, skipping", lib_r.name);
+        } else {
+            let ids: Vec<&str> = ids.split(';').filter(|id| !id.is_empty()).collect();
+            let tracks = searcher.tracks(&ids).await?;
+            let (scores, tracks) = opts.match_opts.score_tracks(&lib_r, tracks);
+            let answer = ui
+                .choose_track(
+                    &lib_r,
+                    tracks,
+                    scores,
+                    &opts.match_opts,
+                    &mut searcher,
+                    false,
+                )
+                .await?;
+            let map_r = match answer {
+                Ans::Chosen(id) => lib_r.to_map_record(&id),
+                Ans::NotFound => lib_r.to_map_record("Not found"),
+                Ans::Skip => {
+                    info!("\"{}\" skipped, it stays in the review file", lib_r.name);
+                    i += 1;
+                    continue;
+                }
+                // undo is never offered here
+                Ans::Undo => unreachable!(),
+                Ans::Quit => break,
+            };
+            info!("\"{}\" added with id: {}", map_r.name, map_r.sp_id);
+            map.push(map_r);
+            write_map(&map_path, &mut map)?;
+        }
+        queue.remove(i);
+        write_queue(&review_path, &queue)?;
+    }
+
+    ui.close();
+    if queue.is_empty() {
+        fs::remove_file(review_path)?;
+        info!("Review complete");
+    } else {
+        info!(
+            "{} tracks left in {} for the next review",
+            queue.len(),
+            review_path.to_string_lossy()
+        );
+    }
+    Ok(())
+}
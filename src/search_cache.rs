@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{spotify::SpTrack, unix_time};
+
+/// Stored in the user's cache directory, one json entry per line, later lines win
+const CACHE_FILE_NAME: &str = "search_cache.jsonl";
+pub const DEFAULT_TTL_DAYS: u64 = 30;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    /// seconds since the unix epoch
+    searched_at: u64,
+    tracks: Vec<SpTrack>,
+}
+
+/// Search results from previous runs, so that remapping doesn't repeat the same searches
+pub struct SearchCache {
+    entries: HashMap<String, Entry>,
+    writer: BufWriter<File>,
+    ttl: Duration,
+    refresh: bool,
+}
+
+fn cache_path() -> anyhow::Result<PathBuf> {
+    Ok(dirs::cache_dir()
+        .ok_or(anyhow!("Could not find a cache directory for this user"))?
+        .join("cspotv")
+        .join(CACHE_FILE_NAME))
+}
+
+impl SearchCache {
+    /// Loads the cache, which gives back results no older than `ttl_days`. With `refresh`, nothing
+    /// is read from it but fresh results are still saved
+    pub fn open(ttl_days: u64, refresh: bool) -> anyhow::Result<Self> {
+        let ttl = Duration::from_secs(ttl_days * 24 * 60 * 60);
+        let path = cache_path()?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut entries = HashMap::new();
+        let mut stale = false;
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines() {
+                match serde_json::from_str::<Entry>(&line?) {
+                    Ok(entry) => {
+                        stale |= entries.insert(entry.key.clone(), entry).is_some();
+                    }
+                    Err(err) => {
+                        warn!("Ignoring unreadable search cache entry: {}", err);
+                        stale = true;
+                    }
+                }
+            }
+        }
+        // rewrite without the overwritten and unreadable entries so the file doesn't grow forever.
+        // Expired ones are kept, as other commands and runs can be given a longer ttl
+        let file = if stale {
+            let tmp_path = path.with_extension("tmp");
+            let mut wtr = BufWriter::new(File::create(&tmp_path)?);
+            for entry in entries.values() {
+                serde_json::to_writer(&mut wtr, entry)?;
+                wtr.write_all(b"\n")?;
+            }
+            wtr.flush()?;
+            drop(wtr);
+            fs::rename(&tmp_path, &path)?;
+            File::options().append(true).open(&path)?
+        } else {
+            File::options().create(true).append(true).open(&path)?
+        };
+        Ok(Self {
+            entries,
+            writer: BufWriter::new(file),
+            ttl,
+            refresh,
+        })
+    }
+
+    fn key(query: &str, market: &str, limit: u32) -> String {
+        format!("{}|{}|{}", market, limit, query)
+    }
+
+    pub fn get(&self, query: &str, market: &str, limit: u32) -> Option<&[SpTrack]> {
+        if self.refresh {
+            return None;
+        }
+        self.entries
+            .get(&Self::key(query, market, limit))
+            .filter(|entry| unix_time().saturating_sub(entry.searched_at) <= self.ttl.as_secs())
+            .map(|entry| entry.tracks.as_slice())
+    }
+
+    pub fn insert(
+        &mut self,
+        query: &str,
+        market: &str,
+        limit: u32,
+        tracks: Vec<SpTrack>,
+    ) -> anyhow::Result<()> {
+        let entry = Entry {
+            key: Self::key(query, market, limit),
+            searched_at: unix_time(),
+            tracks,
+        };
+        serde_json::to_writer(&mut self.writer, &entry)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        self.entries.insert(entry.key.clone(), entry);
+        Ok(())
+    }
+}
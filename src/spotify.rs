@@ -1,81 +1,238 @@
 use std::{
-    fs,
+    fs::{self, File},
     io::{self, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
 
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 use spotify_rs::{
     auth::{NoVerifier, Token},
     client::Client,
-    model::{track::Track, PlayableItem},
-    AuthCodeClient, AuthCodeFlow, ClientCredsClient, ClientCredsFlow, RedirectUrl,
+    model::{search::Item, track::Track, PlayableItem},
+    AuthCodePkceClient, AuthCodePkceFlow, ClientCredsClient, ClientCredsFlow, RedirectUrl,
 };
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Mutex as AsyncMutex},
+    time::timeout,
+};
+use url::Url;
 
-const CLIENT_ID: &str = "fed3e6de8e3e4fe481b4020cdb72342e";
-const CLIENT_SECRET_PATH: &str = "client_secret.txt";
+use crate::{
+    config::Config,
+    fmt_duration,
+    retry::{with_retry, Retry},
+};
 
-pub struct Tr {
-    pub name: String,
-    pub id: String,
-    pub pos: u32,
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// A connection to the loopback address is dropped if it hasn't sent a request by then
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Stored in the user's config directory, keeps you logged in between runs. Each profile gets
+/// its own, as they may use different spotify apps
+const TOKEN_FILE_NAME: &str = "token";
+
+pub type AuthcSp = Client<Token, AuthCodePkceFlow, NoVerifier>;
+
+/// A client for the commands that only look up public data
+pub enum SearchSp {
+    Creds(Client<Token, ClientCredsFlow, NoVerifier>),
+    User(AuthcSp),
 }
 
-pub fn search_str(q: &str, track: &str, album: &str, artist: &str) -> String {
-    let mut out = String::new();
-    if !q.trim().is_empty() {
-        out += &q.replace(" ", "+");
-        out += " ";
+impl SearchSp {
+    pub async fn search(
+        &mut self,
+        query: &str,
+        market: &str,
+        limit: u32,
+    ) -> spotify_rs::SpotifyResult<Vec<SpTrack>> {
+        let results = match self {
+            SearchSp::Creds(sp) => {
+                with_retry!(
+                    Retry::new("Search"),
+                    sp.search(query, &[Item::Track])
+                        .market(market)
+                        .limit(limit)
+                        .get()
+                )
+            }
+            SearchSp::User(sp) => {
+                with_retry!(
+                    Retry::new("Search"),
+                    sp.search(query, &[Item::Track])
+                        .market(market)
+                        .limit(limit)
+                        .get()
+                )
+            }
+        }?;
+        Ok(results
+            .tracks
+            .map(|page| page.items.into_iter().map(SpTrack::from).collect())
+            .unwrap_or_default())
     }
-    if !track.trim().is_empty() {
-        out += "track:";
-        out += &track.replace(" ", "+");
-        out += " ";
+
+    pub async fn track(&mut self, id: &str) -> spotify_rs::SpotifyResult<SpTrack> {
+        let track = match self {
+            SearchSp::Creds(sp) => {
+                with_retry!(Retry::new("Track lookup"), sp.track(id).get())
+            }
+            SearchSp::User(sp) => {
+                with_retry!(Retry::new("Track lookup"), sp.track(id).get())
+            }
+        }?;
+        Ok(track.into())
     }
-    if !album.trim().is_empty() {
-        out += "album:";
-        out += &album.replace(" ", "+");
-        out += " ";
+
+    pub async fn tracks(&mut self, ids: &[&str]) -> spotify_rs::SpotifyResult<Vec<SpTrack>> {
+        let tracks = match self {
+            SearchSp::Creds(sp) => {
+                with_retry!(Retry::new("Track lookup"), sp.tracks(ids).get())
+            }
+            SearchSp::User(sp) => {
+                with_retry!(Retry::new("Track lookup"), sp.tracks(ids).get())
+            }
+        }?;
+        Ok(tracks.into_iter().map(SpTrack::from).collect())
     }
-    if !artist.trim().is_empty() {
-        out += "artist:";
-        out += &artist.replace(" ", "+");
-        out += " ";
+}
+
+/// The parts of a spotify track we use, kept separately so search results can be cached
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SpTrack {
+    pub id: String,
+    pub name: String,
+    pub album: String,
+    pub artists: Vec<String>,
+    pub release_date: String,
+    pub duration_ms: u32,
+    pub isrc: Option<String>,
+    // not in search results cached before these were added
+    #[serde(default)]
+    pub explicit: bool,
+    #[serde(default)]
+    pub popularity: Option<u32>,
+}
+
+impl From<Track> for SpTrack {
+    fn from(track: Track) -> Self {
+        SpTrack {
+            id: track.id,
+            name: track.name,
+            album: track.album.name,
+            artists: track.artists.into_iter().map(|a| a.name).collect(),
+            release_date: track.album.release_date,
+            duration_ms: track.duration_ms,
+            isrc: track.external_ids.isrc,
+            explicit: track.explicit,
+            popularity: Some(track.popularity),
+        }
     }
-    if !out.is_empty() {
-        out.remove(out.len() - 1);
+}
+
+/// Only the refresh token, as spotify-rs can only make a client from a refresh token, not from an
+/// access token we kept
+#[derive(Serialize, Deserialize)]
+struct StoredToken {
+    refresh_token: String,
+}
+
+pub struct Tr {
+    pub name: String,
+    pub id: String,
+    pub pos: u32,
+}
+
+/// The bare id from a track id, a `spotify:track:` URI or an open.spotify.com share link
+pub fn parse_track_id(input: &str) -> anyhow::Result<String> {
+    let input = input.trim();
+    let id = if let Some(rest) = input.strip_prefix("spotify:") {
+        match rest.split_once(':') {
+            Some(("track", id)) => id.to_owned(),
+            _ => return Err(anyhow!("\"{}\" isn't a track URI", input)),
+        }
+    } else if input.contains("spotify.com") {
+        let url = if input.contains("://") {
+            Url::parse(input)?
+        } else {
+            Url::parse(&(String::from("https://") + input))?
+        };
+        // links can have a locale before the type, e.g. /intl-de/track/<id>
+        let mut segments = url.path_segments().into_iter().flatten();
+        segments
+            .find(|seg| *seg == "track")
+            .and_then(|_| segments.next())
+            .ok_or(anyhow!("\"{}\" isn't a link to a track", input))?
+            .to_owned()
+    } else {
+        input.to_owned()
+    };
+    if id.len() == 22 && id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Ok(id)
+    } else {
+        Err(anyhow!("\"{}\" isn't a valid spotify track id", id))
     }
-    out
 }
 
-pub fn print_track(track: &Track) {
+/// Shows how much longer or shorter the track is than `duration_ms`, the file's duration
+pub fn print_track(track: &SpTrack, duration_ms: Option<u32>) {
     println!("Name: {}", track.name);
-    println!("Album: {}", track.album.name);
-    let artists: Vec<String> = track
-        .artists
-        .iter()
-        .map(|artist| artist.name.to_owned())
-        .collect();
-    if artists.len() == 1 {
-        println!("Artist: {}", artists[0]);
+    println!("Album: {}", track.album);
+    if track.artists.len() == 1 {
+        println!("Artist: {}", track.artists[0]);
     } else {
-        println!("Artist: {:?}", artists);
+        println!("Artist: {:?}", track.artists);
+    }
+    println!("Date: {}", track.release_date);
+    println!("Duration: {}", fmt_track_duration(track, duration_ms));
+}
+
+/// The track's duration, and how much longer or shorter it is than the file if that is known,
+/// e.g. "3:05 (+0:02)"
+pub fn fmt_track_duration(track: &SpTrack, duration_ms: Option<u32>) -> String {
+    match duration_ms {
+        Some(duration_ms) => {
+            let sign = if track.duration_ms >= duration_ms {
+                '+'
+            } else {
+                '-'
+            };
+            format!(
+                "{} ({}{})",
+                fmt_duration(track.duration_ms),
+                sign,
+                fmt_duration(track.duration_ms.abs_diff(duration_ms))
+            )
+        }
+        None => fmt_duration(track.duration_ms),
     }
-    println!("Date: {}", track.album.release_date);
 }
 
 pub async fn get_all_playlist_tracks(
-    authc_sp: &mut Client<Token, AuthCodeFlow, NoVerifier>,
+    authc_sp: &mut AuthcSp,
     playlist_id: &str,
 ) -> anyhow::Result<Vec<Tr>> {
     let mut playlist_items = Vec::new();
     let limit: u32 = 50;
-    let total = authc_sp.playlist_items(playlist_id).get().await?.total;
+    let total = with_retry!(
+        Retry::new("Fetching the playlist"),
+        authc_sp.playlist_items(playlist_id).get()
+    )?
+    .total;
     for offset in (0..total).step_by(limit as usize) {
-        let page = authc_sp
-            .playlist_items(playlist_id)
-            .limit(limit)
-            .offset(offset)
-            .get()
-            .await?;
+        let page = with_retry!(
+            Retry::new("Fetching the playlist"),
+            authc_sp
+                .playlist_items(playlist_id)
+                .limit(limit)
+                .offset(offset)
+                .get()
+        )?;
         page.items
             .into_iter()
             .enumerate()
@@ -96,36 +253,333 @@ pub async fn get_all_playlist_tracks(
     Ok(playlist_items)
 }
 
-pub async fn get_cred_sp() -> anyhow::Result<Client<Token, ClientCredsFlow, NoVerifier>> {
-    let client_creds_flow =
-        ClientCredsFlow::new(CLIENT_ID, fs::read_to_string(CLIENT_SECRET_PATH)?.trim());
-    Ok(ClientCredsClient::authenticate(client_creds_flow).await?)
+/// Uses the client secret if there is one, otherwise logs in as the user
+pub async fn get_search_sp(config: &Config) -> anyhow::Result<SearchSp> {
+    if let Some(client_secret) = &config.client_secret {
+        let client_creds_flow = ClientCredsFlow::new(&config.client_id, client_secret);
+        return Ok(SearchSp::Creds(
+            ClientCredsClient::authenticate(client_creds_flow).await?,
+        ));
+    }
+    info!("No client secret configured, searching as yourself");
+    Ok(SearchSp::User(get_authc_sp(config).await?))
+}
+
+/// A client used from more than one place, e.g. searching ahead and the chooser
+pub type SharedSearchSp = Arc<AsyncMutex<SearchSp>>;
+
+/// Up to `jobs` clients to search with concurrently. Only one is made when searching with the
+/// user's login, as refreshing it in one client can invalidate it for the others, so anything else
+/// that searches should share these rather than log in again
+pub async fn get_search_sps(config: &Config, jobs: usize) -> anyhow::Result<Vec<SharedSearchSp>> {
+    let mut search_sps = vec![get_search_sp(config).await?];
+    if let SearchSp::Creds(_) = search_sps[0] {
+        while search_sps.len() < jobs {
+            search_sps.push(get_search_sp(config).await?);
+        }
+    }
+    Ok(search_sps
+        .into_iter()
+        .map(|search_sp| Arc::new(AsyncMutex::new(search_sp)))
+        .collect())
+}
+
+fn token_path(config: &Config) -> anyhow::Result<PathBuf> {
+    let mut file_name = String::from(TOKEN_FILE_NAME);
+    if let Some(profile) = &config.profile {
+        file_name += "_";
+        file_name += profile;
+    }
+    file_name += ".json";
+    Ok(dirs::config_dir()
+        .ok_or(anyhow!("Could not find a config directory for this user"))?
+        .join("cspotv")
+        .join(file_name))
+}
+
+fn load_refresh_token(config: &Config) -> Option<String> {
+    let contents = fs::read_to_string(token_path(config).ok()?).ok()?;
+    match serde_json::from_str::<StoredToken>(&contents) {
+        Ok(stored) => Some(stored.refresh_token),
+        Err(err) => {
+            warn!("Ignoring unreadable stored login: {}", err);
+            None
+        }
+    }
+}
+
+/// Writes the token where only the current user can read it
+fn save_refresh_token(refresh_token: &str, config: &Config) -> anyhow::Result<()> {
+    let path = token_path(config)?;
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    let mut options = File::options();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+        options.mode(0o600);
+        if path.exists() {
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    let mut file = options.open(&path)?;
+    serde_json::to_writer(
+        &mut file,
+        &StoredToken {
+            refresh_token: refresh_token.to_owned(),
+        },
+    )?;
+    Ok(())
+}
+
+pub fn logout(config: &Config) -> anyhow::Result<()> {
+    let path = token_path(config)?;
+    if path.exists() {
+        fs::remove_file(&path)?;
+        info!("Removed stored login from {}", path.to_string_lossy());
+    } else {
+        info!("Not logged in, nothing to remove");
+    }
+    Ok(())
 }
 
-pub async fn get_authc_sp() -> anyhow::Result<Client<Token, AuthCodeFlow, NoVerifier>> {
-    let redirect_url = RedirectUrl::new("http://127.0.0.1".to_owned())?;
+/// PKCE, so that logging in doesn't need the client secret
+fn authc_flow(config: &Config) -> AuthCodePkceFlow {
     let scopes = vec![
         "playlist-read-private",
         "playlist-modify-private",
         "playlist-modify-public",
     ];
-    let auth_code_flow = AuthCodeFlow::new(
-        CLIENT_ID,
-        fs::read_to_string(CLIENT_SECRET_PATH)?.trim(),
-        scopes,
-    );
+    AuthCodePkceFlow::new(&config.client_id, scopes)
+}
 
-    let (auth_client, url) = AuthCodeClient::new(auth_code_flow, redirect_url, true);
-    println!("Enter the following url into a browser:\n\n\t{}\n", url);
-    // TODO see if u can store the auth stuff and only refresh it if its invalid
-    // TODO host a page with hyper, open the url with webbrowser, and get the auth automatically
-    print!("Then paste the resuting localhost url here: ");
+/// Logs in with a refresh token, and stores whichever refresh token should be used next time
+async fn authc_sp_from_refresh_token(
+    refresh_token: String,
+    config: &Config,
+) -> anyhow::Result<AuthcSp> {
+    let authc_sp =
+        AuthcSp::from_refresh_token(authc_flow(config), true, refresh_token.clone()).await?;
+    // spotify doesn't always hand out a new refresh token, in which case the old one stays valid
+    save_refresh_token(authc_sp.refresh_token().unwrap_or(&refresh_token), config)?;
+    Ok(authc_sp)
+}
+
+/// Gets a fresh access token for `authc_sp`, for when it was rejected or couldn't be refreshed
+pub async fn reauth_authc_sp(authc_sp: &mut AuthcSp, config: &Config) -> anyhow::Result<()> {
+    let refresh_token = authc_sp
+        .refresh_token()
+        .map(str::to_owned)
+        .or_else(|| load_refresh_token(config))
+        .ok_or(anyhow!(
+            "Login expired, please run the command again to log in"
+        ))?;
+    info!("Refreshing login...");
+    *authc_sp = authc_sp_from_refresh_token(refresh_token, config).await?;
+    Ok(())
+}
+
+/// The code spotify redirected to `url` with, as long as `url` carries the expected state
+fn parse_redirect(url: &Url, expected_state: &str) -> anyhow::Result<String> {
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, val)| val.into_owned())
+    };
+    if let Some(error) = param("error") {
+        if error == "access_denied" {
+            return Err(anyhow!("Login was cancelled, access to spotify was denied"));
+        }
+        return Err(anyhow!("Spotify login failed: {}", error));
+    }
+    if param("state").as_deref() != Some(expected_state) {
+        return Err(anyhow!(
+            "Login response state didn't match the request, please try logging in again"
+        ));
+    }
+    param("code").ok_or(anyhow!("Login response is missing the authorization code"))
+}
+
+/// Answers requests to the loopback address until spotify redirects the browser to `redirect_url`
+async fn capture_redirect(listener: TcpListener, redirect_url: &Url) -> anyhow::Result<Url> {
+    let (tx, mut rx) = mpsc::channel(1);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let (tx, redirect_url) = (tx.clone(), redirect_url.clone());
+                // each connection is answered on its own, as browsers open idle connections ahead
+                // of time that would otherwise hold up the one with the redirect
+                tokio::spawn(async move {
+                    let answered = timeout(REQUEST_TIMEOUT, answer_request(stream, &redirect_url));
+                    if let Ok(Ok(Some(url))) = answered.await {
+                        let _ = tx.send(url).await;
+                    }
+                });
+            }
+            Some(url) = rx.recv() => return Ok(url),
+        }
+    }
+}
+
+/// Reads one request, and returns its url if it is the redirect
+async fn answer_request(mut stream: TcpStream, redirect_url: &Url) -> anyhow::Result<Option<Url>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 16 * 1024 {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    // e.g. "GET /callback?code=...&state=... HTTP/1.1"
+    let target = request.split_whitespace().nth(1).unwrap_or("/");
+    let url = redirect_url.join(target)?;
+    if url.path() != redirect_url.path() {
+        stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Ok(None);
+    }
+    let body = "cspotv received the login, you can close this tab.";
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+    Ok(Some(url))
+}
+
+fn paste_redirect() -> anyhow::Result<Url> {
+    print!("Then paste the resulting localhost url here: ");
     io::stdout().flush()?;
     let mut auth_url = String::new();
     io::stdin().read_line(&mut auth_url)?;
     println!("\n");
-    let split: Vec<&str> = auth_url.trim().split(['=', '&']).collect();
-    let auth_code = split[1].to_owned();
-    let csrf_token = split[3].to_owned();
-    Ok(auth_client.authenticate(auth_code, csrf_token).await?)
+    Ok(Url::parse(auth_url.trim())?)
+}
+
+pub async fn get_authc_sp(config: &Config) -> anyhow::Result<AuthcSp> {
+    if let Some(refresh_token) = load_refresh_token(config) {
+        match authc_sp_from_refresh_token(refresh_token, config).await {
+            Ok(authc_sp) => return Ok(authc_sp),
+            Err(err) => warn!("Stored login no longer works, logging in again: {}", err),
+        }
+    }
+
+    let redirect_url = Url::parse(&config.redirect_url)
+        .with_context(|| format!("invalid redirect url \"{}\"", config.redirect_url))?;
+    let (auth_client, url) = AuthCodePkceClient::new(
+        authc_flow(config),
+        RedirectUrl::from_url(redirect_url.clone()),
+        true,
+    );
+    let state = url
+        .query_pairs()
+        .find(|(key, _)| key == "state")
+        .map(|(_, val)| val.into_owned())
+        .unwrap_or_default();
+    println!("Enter the following url into a browser:\n\n\t{}\n", url);
+    // TODO open the url with webbrowser
+    let addr = (
+        redirect_url.host_str().unwrap_or("127.0.0.1"),
+        redirect_url.port_or_known_default().unwrap_or(80),
+    );
+    let returned_url = match TcpListener::bind(addr).await {
+        Ok(listener) => {
+            println!("Waiting for the login to complete...");
+            timeout(LOGIN_TIMEOUT, capture_redirect(listener, &redirect_url))
+                .await
+                .map_err(|_| anyhow!("Timed out waiting for the login to complete"))??
+        }
+        Err(err) => {
+            warn!(
+                "Could not listen on {}:{} for the login ({}), falling back to pasting",
+                addr.0, addr.1, err
+            );
+            paste_redirect()?
+        }
+    };
+    let auth_code = parse_redirect(&returned_url, &state)?;
+    let authc_sp = auth_client.authenticate(auth_code, state).await?;
+    if let Some(refresh_token) = authc_sp.refresh_token() {
+        save_refresh_token(refresh_token, config)?;
+    }
+    Ok(authc_sp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "4uLU6hMCjMI75M1A2tKUQC";
+
+    #[test]
+    fn bare_ids_are_kept() {
+        assert_eq!(parse_track_id(ID).unwrap(), ID);
+        assert_eq!(parse_track_id(&format!("  {}\n", ID)).unwrap(), ID);
+    }
+
+    #[test]
+    fn track_uris_are_parsed() {
+        assert_eq!(
+            parse_track_id(&format!("spotify:track:{}", ID)).unwrap(),
+            ID
+        );
+    }
+
+    #[test]
+    fn share_links_are_parsed() {
+        for link in [
+            format!("https://open.spotify.com/track/{}", ID),
+            format!("https://open.spotify.com/track/{}?si=1a2b3c4d5e6f7a8b", ID),
+            format!(
+                "https://open.spotify.com/intl-de/track/{}?si=1a2b3c4d5e6f7a8b",
+                ID
+            ),
+            format!("open.spotify.com/track/{}", ID),
+        ] {
+            assert_eq!(parse_track_id(&link).unwrap(), ID, "{}", link);
+        }
+    }
+
+    #[test]
+    fn other_uris_and_links_are_rejected() {
+        for input in [
+            format!("spotify:album:{}", ID),
+            format!("spotify:playlist:{}", ID),
+            format!("https://open.spotify.com/album/{}", ID),
+            format!(
+                "https://open.spotify.com/playlist/{}?si=1a2b3c4d5e6f7a8b",
+                ID
+            ),
+            format!("https://open.spotify.com/intl-de/artist/{}", ID),
+        ] {
+            assert!(parse_track_id(&input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn malformed_ids_are_rejected() {
+        for input in [
+            "",
+            "4uLU6hMCjMI75M1A2tKUQ",
+            "4uLU6hMCjMI75M1A2tKUQC1",
+            "4uLU6hMCjMI75M1A2tKU-C",
+            "spotify:track:",
+            "https://open.spotify.com/track/",
+            "https://open.spotify.com/track/not-an-id",
+        ] {
+            assert!(parse_track_id(input).is_err(), "{}", input);
+        }
+    }
 }
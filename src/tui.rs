@@ -0,0 +1,349 @@
+use std::sync::Mutex;
+
+use log::{Level, Log, Metadata, Record};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, Cell, Paragraph, Row, Table, TableState, Wrap},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    map::{Ans, Candidates, MatchOpts, Searcher},
+    spotify::{fmt_track_duration, parse_track_id, SpTrack},
+    LibRec,
+};
+
+/// Lines logged while the terminal UI is up, which would otherwise be drawn over it. Shown in the
+/// UI instead, and logged as usual once it closes
+static CAPTURED: Mutex<Option<Vec<(Level, String)>>> = Mutex::new(None);
+
+/// colog's logger, except while the terminal UI is up
+struct Logger {
+    inner: Box<dyn Log>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(captured) = CAPTURED.lock().unwrap().as_mut() {
+            captured.push((record.level(), record.args().to_string()));
+            return;
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+pub fn init_logging() {
+    let inner = colog::default_builder().build();
+    log::set_max_level(inner.filter());
+    log::set_logger(Box::leak(Box::new(Logger {
+        inner: Box::new(inner),
+    })))
+    .expect("logger already set");
+}
+
+const HELP: &str =
+    "↑/↓ select  enter use it  / filter  s enter id  m more  r search  n not found  \
+u undo  k skip  q quit";
+
+/// What is being typed at the bottom of the screen
+enum Input {
+    Filter,
+    Id,
+    Query,
+}
+
+/// Full screen chooser for `map --tui`, the terminal is restored when it's dropped
+pub struct Tui {
+    terminal: DefaultTerminal,
+}
+
+impl Tui {
+    pub fn new() -> anyhow::Result<Self> {
+        // only captured once there's a UI to show it, and a Tui to log it when dropped
+        let terminal = ratatui::try_init()?;
+        *CAPTURED.lock().unwrap() = Some(Vec::new());
+        Ok(Self { terminal })
+    }
+
+    /// Same as `map::choose_track`, with the file on the left and its search results on the right
+    pub async fn choose_track(
+        &mut self,
+        lib_r: &LibRec,
+        tracks: Vec<SpTrack>,
+        scores: Vec<f64>,
+        match_opts: &MatchOpts,
+        searcher: &mut Searcher<'_>,
+        can_undo: bool,
+    ) -> anyhow::Result<Ans> {
+        let mut chooser = Chooser {
+            lib_r,
+            candidates: Candidates::new(tracks, scores),
+            table: TableState::default().with_selected(0),
+            filter: String::new(),
+            input: None,
+            text: String::new(),
+            status: String::new(),
+        };
+        loop {
+            self.terminal.draw(|frame| chooser.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            chooser.status.clear();
+
+            if let Some(input) = &chooser.input {
+                match key.code {
+                    KeyCode::Char(c) => chooser.text.push(c),
+                    KeyCode::Backspace => {
+                        chooser.text.pop();
+                    }
+                    KeyCode::Esc => {
+                        if matches!(input, Input::Filter) {
+                            chooser.filter.clear();
+                        }
+                        chooser.input = None;
+                        chooser.text.clear();
+                    }
+                    KeyCode::Enter => {
+                        let text = chooser.text.trim().to_owned();
+                        match chooser.input.take().unwrap() {
+                            Input::Filter => {}
+                            Input::Id if !text.is_empty() => {
+                                chooser.status = "Looking up the track...".to_owned();
+                                self.terminal.draw(|frame| chooser.draw(frame))?;
+                                chooser.status = match parse_track_id(&text) {
+                                    Ok(id) => match searcher.track(&id).await? {
+                                        Some(track) => {
+                                            chooser.filter.clear();
+                                            chooser.candidates.push_front(lib_r, match_opts, track);
+                                            chooser.table.select(Some(0));
+                                            "Entered track added at the top, press enter to use it"
+                                                .to_owned()
+                                        }
+                                        None => format!("Spotify has no track with id {}", id),
+                                    },
+                                    Err(err) => err.to_string(),
+                                };
+                            }
+                            Input::Query if !text.is_empty() => {
+                                chooser.status = "Searching...".to_owned();
+                                self.terminal.draw(|frame| chooser.draw(frame))?;
+                                chooser.status = if chooser
+                                    .candidates
+                                    .search(&text, lib_r, match_opts, searcher)
+                                    .await?
+                                {
+                                    chooser.filter.clear();
+                                    chooser.table.select(Some(0));
+                                    String::new()
+                                } else {
+                                    format!("Nothing found for \"{}\"", text)
+                                };
+                            }
+                            _ => {}
+                        }
+                        chooser.text.clear();
+                    }
+                    _ => {}
+                }
+                if matches!(chooser.input, Some(Input::Filter)) {
+                    chooser.filter = chooser.text.clone();
+                    chooser.table.select(Some(0));
+                }
+                continue;
+            }
+
+            let visible = chooser.visible();
+            match key.code {
+                KeyCode::Up => chooser.table.select_previous(),
+                KeyCode::Down => chooser.table.select_next(),
+                KeyCode::Home => chooser.table.select_first(),
+                KeyCode::End => chooser.table.select_last(),
+                KeyCode::Enter => {
+                    let selected = chooser.table.selected().and_then(|i| visible.get(i));
+                    if let Some(&i) = selected {
+                        return Ok(Ans::Chosen(chooser.candidates.tracks[i].id.clone()));
+                    }
+                }
+                KeyCode::Char('/') => {
+                    chooser.input = Some(Input::Filter);
+                    chooser.text = chooser.filter.clone();
+                }
+                KeyCode::Char('s') => chooser.input = Some(Input::Id),
+                KeyCode::Char('r') => chooser.input = Some(Input::Query),
+                KeyCode::Char('m') => {
+                    chooser.status = "Searching...".to_owned();
+                    self.terminal.draw(|frame| chooser.draw(frame))?;
+                    let added = chooser.candidates.more(lib_r, match_opts, searcher).await?;
+                    chooser.status = if added == 0 {
+                        "No more results".to_owned()
+                    } else {
+                        format!("{} more results", added)
+                    };
+                }
+                KeyCode::Char('n') => return Ok(Ans::NotFound),
+                KeyCode::Char('u') if can_undo => return Ok(Ans::Undo),
+                KeyCode::Char('u') => chooser.status = "Nothing to undo".to_owned(),
+                KeyCode::Char('k') => return Ok(Ans::Skip),
+                KeyCode::Char('q') => return Ok(Ans::Quit),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        ratatui::restore();
+        let captured = CAPTURED.lock().unwrap().take().unwrap_or_default();
+        for (level, line) in captured {
+            log::log!(level, "{}", line);
+        }
+    }
+}
+
+struct Chooser<'a> {
+    lib_r: &'a LibRec,
+    candidates: Candidates,
+    /// selection among the results that pass the filter
+    table: TableState,
+    filter: String,
+    input: Option<Input>,
+    text: String,
+    status: String,
+}
+
+impl Chooser<'_> {
+    /// Indices of the results whose name, album or artists contain the filter
+    fn visible(&self) -> Vec<usize> {
+        let filter = self.filter.to_lowercase();
+        self.candidates
+            .tracks
+            .iter()
+            .enumerate()
+            .filter(|(_, tr)| {
+                tr.name.to_lowercase().contains(&filter)
+                    || tr.album.to_lowercase().contains(&filter)
+                    || tr
+                        .artists
+                        .iter()
+                        .any(|artist| artist.to_lowercase().contains(&filter))
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, log_area, status_area] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Length(6),
+            Constraint::Length(2),
+        ])
+        .areas(frame.area());
+        let [lib_area, results_area] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)])
+                .areas(main);
+
+        frame.render_widget(
+            Paragraph::new(self.lib_r.to_string())
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().title(" Track to match ")),
+            lib_area,
+        );
+        self.draw_results(frame, results_area);
+        draw_log(frame, log_area);
+
+        let prompt = match self.input {
+            Some(Input::Filter) => Some("Filter: "),
+            Some(Input::Id) => Some("Spotify id, URI or link: "),
+            Some(Input::Query) => Some("Search for: "),
+            None => None,
+        };
+        let first_line = match prompt {
+            Some(prompt) => Line::from(format!("{}{}", prompt, self.text)).bold(),
+            None => Line::from(self.status.as_str()).yellow(),
+        };
+        frame.render_widget(
+            Paragraph::new(vec![first_line, Line::from(HELP).dim()]),
+            status_area,
+        );
+    }
+
+    fn draw_results(&mut self, frame: &mut Frame, area: Rect) {
+        let duration_ms = self.lib_r.duration_ms;
+        let rows = self.visible().into_iter().map(|i| {
+            let tr = &self.candidates.tracks[i];
+            Row::new([
+                Cell::from((i + 1).to_string()),
+                Cell::from(format!("{:.0}%", self.candidates.scores[i] * 100.0)),
+                Cell::from(tr.name.as_str()),
+                Cell::from(tr.artists.join(", ")),
+                Cell::from(tr.album.as_str()),
+                Cell::from(tr.release_date.as_str()),
+                Cell::from(fmt_track_duration(tr, duration_ms)),
+                Cell::from(if tr.explicit { "E" } else { "" }),
+                Cell::from(tr.popularity.map(|p| p.to_string()).unwrap_or_default()),
+            ])
+        });
+        let widths = [
+            Constraint::Length(3),
+            Constraint::Length(5),
+            Constraint::Fill(3),
+            Constraint::Fill(2),
+            Constraint::Fill(2),
+            Constraint::Length(10),
+            Constraint::Length(14),
+            Constraint::Length(1),
+            Constraint::Length(4),
+        ];
+        let header = Row::new([
+            "#", "Match", "Name", "Artists", "Album", "Released", "Duration", "E", "Pop",
+        ])
+        .bold();
+        let title = if self.filter.is_empty() {
+            " Search results ".to_owned()
+        } else {
+            format!(" Search results matching \"{}\" ", self.filter)
+        };
+        let table = Table::new(rows, widths)
+            .header(header)
+            .row_highlight_style(Style::new().reversed())
+            .block(Block::bordered().title(title));
+        frame.render_stateful_widget(table, area, &mut self.table);
+    }
+}
+
+fn draw_log(frame: &mut Frame, area: Rect) {
+    let captured = CAPTURED.lock().unwrap();
+    let captured = captured.as_deref().unwrap_or_default();
+    let shown = area.height.saturating_sub(2) as usize;
+    let lines: Vec<Line> = captured[captured.len().saturating_sub(shown)..]
+        .iter()
+        .map(|(level, line)| match level {
+            Level::Error => Line::from(line.as_str()).red(),
+            Level::Warn => Line::from(line.as_str()).yellow(),
+            _ => Line::from(line.as_str()),
+        })
+        .collect();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Log ")),
+        area,
+    );
+}